@@ -0,0 +1,90 @@
+use std::{fs::{self, File}, path::Path};
+
+use image::DynamicImage;
+use ratatui::text::Text;
+use symphonia::core::{
+    io::MediaSourceStream,
+    meta::{Limit, MetadataOptions, StandardVisualKey, Visual},
+};
+use symphonia::default::get_probe;
+
+use crate::img_to_ascii::to_ascii_text;
+
+/// Sidecar image names checked (case-insensitively) next to a song that has no embedded picture
+const SIDECAR_NAMES: [&str; 6] = ["cover.jpg", "cover.png", "folder.jpg", "folder.png", "front.jpg", "front.png"];
+
+/// Album art of the song in the player panel. The picture is only extracted when the song changes and only
+/// re-rendered when the panel changes size, not on every frame
+#[derive(Default)]
+pub struct AlbumArt {
+    /// path of the song the picture belongs to, set even if it has none so files without art aren't re-read
+    song_path: Option<String>,
+    image: Option<DynamicImage>,
+    rendered: Option<((u16, u16), Text<'static>)>,
+}
+
+impl AlbumArt {
+    /// The art for the song at `song_path` rendered to fit `width` x `height` cells, `None` if it has none
+    pub fn get(&mut self, song_path: &str, width: u16, height: u16) -> Option<&Text<'static>> {
+        self.load(song_path);
+        let image = self.image.as_ref()?;
+        if self.rendered.as_ref().map(|(size, _)| *size) != Some((width, height)) {
+            self.rendered = Some(((width, height), to_ascii_text(image, width, height)));
+        }
+        self.rendered.as_ref().map(|(_, text)| text)
+    }
+
+    /// Whether the song at `song_path` has any art, extracting it if it wasn't looked at yet
+    pub fn has_art(&mut self, song_path: &str) -> bool {
+        self.load(song_path);
+        self.image.is_some()
+    }
+
+    fn load(&mut self, song_path: &str) {
+        if self.song_path.as_deref() == Some(song_path) { return; }
+        self.image = load_cover(Path::new(song_path));
+        self.song_path = Some(song_path.to_owned());
+        self.rendered = None;
+    }
+}
+
+/// The embedded front cover (APIC frame, `covr` atom, ...) of the file at `path`, or a sidecar image from its
+/// directory
+pub fn load_cover(path: &Path) -> Option<DynamicImage> {
+    embedded_cover(path)
+        .and_then(|data| image::load_from_memory(&data).ok())
+        .or_else(|| sidecar_cover(path))
+}
+
+fn embedded_cover(path: &Path) -> Option<Box<[u8]>> {
+    let file = File::open(path).ok()?;
+    let mut probed = get_probe().format(
+        &Default::default(),
+        MediaSourceStream::new(Box::new(file), Default::default()),
+        &Default::default(),
+        &MetadataOptions { limit_metadata_bytes: Limit::None, limit_visual_bytes: Limit::None },
+    ).ok()?;
+
+    let mut visuals: Vec<Visual> = Vec::new();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        visuals.extend_from_slice(revision.visuals());
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        visuals.extend_from_slice(revision.visuals());
+    }
+    // prefer the front cover but take whatever picture there is
+    let index = visuals.iter().position(|v| v.usage == Some(StandardVisualKey::FrontCover)).unwrap_or(0);
+    (index < visuals.len()).then(|| visuals.swap_remove(index).data)
+}
+
+fn sidecar_cover(path: &Path) -> Option<DynamicImage> {
+    let dir = path.parent()?;
+    let mut entries: Vec<_> = fs::read_dir(dir).ok()?.filter_map(|e| e.ok()).collect();
+    entries.sort_by_key(|e| {
+        let name = e.file_name().to_string_lossy().to_lowercase();
+        SIDECAR_NAMES.iter().position(|n| *n == name)
+    });
+    entries.iter()
+        .filter(|e| SIDECAR_NAMES.contains(&e.file_name().to_string_lossy().to_lowercase().as_str()))
+        .find_map(|e| image::open(e.path()).ok())
+}
@@ -0,0 +1,152 @@
+use std::{env, fs, path::PathBuf, process};
+
+use color_eyre::{eyre::{bail, eyre, WrapErr}, Result};
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::keymap::KeysConfig;
+
+const USAGE: &str = "usage: horizon [--config FILE] [--root DIR]... [--include GLOB]... [--exclude GLOB]... [--playlists DIR]";
+
+/// Everything that can be set in `config.toml`, with the command line applied on top
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub library: LibraryConfig,
+    pub keys: KeysConfig,
+}
+
+/// Where the library is scanned from and which files are taken into it
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LibraryConfig {
+    /// directories scanned recursively for songs
+    pub roots: Vec<PathBuf>,
+    /// if not empty, only files matching one of these globs (relative to their root) are read
+    pub include: Vec<String>,
+    /// files and directories matching any of these globs (relative to their root) are left out
+    pub exclude: Vec<String>,
+    /// directory saved playlists are written to and read from
+    pub playlists: Option<PathBuf>,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self {
+            roots: vec![PathBuf::from("./music/")],
+            include: Vec::new(),
+            exclude: Vec::new(),
+            playlists: data_dir().map(|d| d.join("playlists")),
+        }
+    }
+}
+
+impl LibraryConfig {
+    pub fn include_patterns(&self) -> Result<Vec<Pattern>> {
+        compile_patterns(&self.include)
+    }
+
+    pub fn exclude_patterns(&self) -> Result<Vec<Pattern>> {
+        compile_patterns(&self.exclude)
+    }
+}
+
+impl Config {
+    /// Reads the config file (`--config` or the default location) and applies the command line arguments over it
+    pub fn load() -> Result<Self> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        let mut config_path: Option<PathBuf> = None;
+        let mut roots: Vec<PathBuf> = Vec::new();
+        let mut include: Vec<String> = Vec::new();
+        let mut exclude: Vec<String> = Vec::new();
+        let mut playlists: Option<PathBuf> = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| eyre!("{} expects a value\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--config" | "-c" => config_path = Some(PathBuf::from(value()?)),
+                "--root" | "-r" => roots.push(expand_home(&value()?)),
+                "--include" => include.push(value()?),
+                "--exclude" => exclude.push(value()?),
+                "--playlists" => playlists = Some(expand_home(&value()?)),
+                "--help" | "-h" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                other => bail!("unknown argument {}\n{}", other, USAGE),
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None => match config_dir().map(|d| d.join("config.toml")).filter(|p| p.exists()) {
+                Some(path) => Self::from_file(&path)?,
+                None => Self::default(),
+            },
+        };
+
+        // roots given on the command line replace the configured ones, patterns add to them
+        if !roots.is_empty() { config.library.roots = roots; }
+        config.library.include.extend(include);
+        config.library.exclude.extend(exclude);
+        if playlists.is_some() { config.library.playlists = playlists; }
+        config.library.roots = config.library.roots.iter().map(|r| expand_home(&r.to_string_lossy())).collect();
+        config.library.playlists = config.library.playlists.map(|p| expand_home(&p.to_string_lossy()));
+
+        // catch broken patterns now rather than in the middle of a scan
+        config.library.include_patterns()?;
+        config.library.exclude_patterns()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self> {
+        let content = fs::read_to_string(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).wrap_err_with(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+/// `$XDG_CONFIG_HOME/horizon`, falling back to `~/.config/horizon`
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .map(|d| d.join("horizon"))
+}
+
+/// `$XDG_CACHE_HOME/horizon`, falling back to `~/.cache/horizon`
+pub fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .map(|d| d.join("horizon"))
+}
+
+/// `$XDG_DATA_HOME/horizon`, falling back to `~/.local/share/horizon`
+pub fn data_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".local").join("share")))
+        .map(|d| d.join("horizon"))
+}
+
+/// `$XDG_STATE_HOME/horizon`, falling back to `~/.local/state/horizon`
+pub fn state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".local").join("state")))
+        .map(|d| d.join("horizon"))
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn compile_patterns(globs: &[String]) -> Result<Vec<Pattern>> {
+    globs.iter()
+        .map(|g| Pattern::new(g).wrap_err_with(|| format!("invalid glob pattern {:?}", g)))
+        .collect()
+}
@@ -0,0 +1,75 @@
+use std::{
+    sync::mpsc::{self, Receiver, RecvError, Sender},
+    thread,
+    time::Duration,
+};
+
+use ratatui::crossterm::event::{self, Event};
+
+use crate::library::Library;
+
+/// Everything the main loop wakes up for
+pub enum AppEvent {
+    /// a key press, resize, ... from the terminal
+    Input(Event),
+    /// the periodic timer fired, used to keep the progress display moving
+    Tick,
+    /// the player has news, pick them up with `Player::poll_events`
+    Player,
+    /// a message from a job running in the background
+    Task(TaskMessage),
+}
+
+/// What background jobs report back to the UI
+pub enum TaskMessage {
+    /// the library scan has read this many files so far
+    ScanProgress(usize),
+    /// the library scan is done, this is the fresh library
+    ScanFinished(Box<Library>),
+}
+
+/// Funnels terminal input, the tick timer, the player and background jobs into one channel so the main loop can
+/// block on all of them at once
+pub struct Events {
+    sender: Sender<AppEvent>,
+    receiver: Receiver<AppEvent>,
+}
+
+impl Events {
+    /// Starts the threads feeding terminal input and ticks every `tick_rate` into the channel
+    pub fn new(tick_rate: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let input = sender.clone();
+        thread::spawn(move || {
+            // stops once reading fails or the main loop is gone
+            while let Ok(event) = event::read() {
+                if input.send(AppEvent::Input(event)).is_err() { break; }
+            }
+        });
+
+        let tick = sender.clone();
+        thread::spawn(move || {
+            while tick.send(AppEvent::Tick).is_ok() {
+                thread::sleep(tick_rate);
+            }
+        });
+
+        Self { sender, receiver }
+    }
+
+    /// A handle for the player and background jobs to send their events with
+    pub fn sender(&self) -> Sender<AppEvent> {
+        self.sender.clone()
+    }
+
+    /// Waits for the next event
+    pub fn next(&self) -> Result<AppEvent, RecvError> {
+        self.receiver.recv()
+    }
+
+    /// The next event if one is already waiting, to drain bursts before redrawing
+    pub fn try_next(&self) -> Option<AppEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use symphonia::core::{
+    codecs::{CodecParameters, CodecRegistry},
+    formats::{FormatOptions, FormatReader, Track},
+    io::{BufReader as BytesReader, MediaSourceStream, MediaSourceStreamOptions},
+    meta::{Limit, MetadataBuilder, MetadataOptions, StandardTagKey, Tag, Value},
+    probe::{Hint, ProbeResult},
+};
+use symphonia_metadata::{id3v2::read_id3v2, riff};
+
+use crate::playlist::SongError;
+
+/// Extensions of the audio files a scan picks up, anything else in the music directories is left alone
+pub const AUDIO_EXTENSIONS: [&str; 13] = [
+    "mp3", "flac", "ogg", "oga", "wav", "wave", "aif", "aiff", "aifc", "m4a", "m4b", "mp4", "aac",
+];
+/// Only readable with the `opus` feature
+const OPUS_EXTENSION: &str = "opus";
+
+/// Biggest tag chunk read from a WAV or AIFF file, anything larger is not a tag anyone meant to write
+const MAX_TAG_CHUNK: u32 = 16 * 1024 * 1024;
+
+pub fn is_audio(path: &Path) -> bool {
+    let Some(extension) = path.extension().map(|e| e.to_string_lossy().to_lowercase()) else { return false };
+    AUDIO_EXTENSIONS.contains(&extension.as_str()) || (cfg!(feature = "opus") && extension == OPUS_EXTENSION)
+}
+
+/// symphonia's decoders plus the ones we bring ourselves
+pub fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        #[cfg(feature = "opus")]
+        registry.register_all::<crate::opus::OpusDecoder>();
+        registry
+    })
+}
+
+/// Opens the file at `path` with the demuxer for its format, for reading its tags and properties as well as for
+/// decoding it. Gapless information is taken from the container, so encoder delay and padding can be cut off
+pub fn probe(path: &Path) -> Result<ProbeResult, SongError> {
+    let file = File::open(path)?;
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) { hint.with_extension(extension); }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        MediaSourceStream::new(Box::new(file), MediaSourceStreamOptions { buffer_len: 1024 * 512 }),
+        &FormatOptions { prebuild_seek_index: true, seek_index_fill_rate: 128, enable_gapless: true },
+        &MetadataOptions { limit_metadata_bytes: Limit::None, limit_visual_bytes: Limit::None },
+    )?;
+    Ok(probed)
+}
+
+/// The track of a file that is listed and played, symphonia's default track: the one the container marks as such, or
+/// the first
+pub fn audio_track(format: &dyn FormatReader) -> Option<&Track> {
+    format.default_track()
+}
+
+/// Length of a track as far as its container tells
+pub fn track_duration(params: &CodecParameters) -> Option<Duration> {
+    params.n_frames.and_then(|frames| match (params.time_base, params.sample_rate) {
+        (Some(time_base), _) => {
+            let time = time_base.calc_time(frames);
+            Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+        }
+        (None, Some(rate)) => Some(Duration::from_secs_f64(frames as f64 / rate as f64)),
+        (None, None) => None,
+    })
+}
+
+/// Tags WAV and AIFF files keep where symphonia doesn't look: an `id3 ` chunk (as written by most taggers) in
+/// either, RIFF INFO lists after the audio data in WAV, and the NAME/AUTH text chunks of AIFF. Empty for every
+/// other kind of file
+pub fn chunk_tags(path: &Path) -> io::Result<Vec<Tag>> {
+    read_chunk_tags(BufReader::new(File::open(path)?))
+}
+
+/// [`chunk_tags`] from an open file
+fn read_chunk_tags(mut file: impl Read + Seek) -> io::Result<Vec<Tag>> {
+    let mut header = [0u8; 12];
+    if file.read_exact(&mut header).is_err() { return Ok(Vec::new()); }
+    // RIFF sizes are little endian, the (Apple) AIFF ones big endian
+    let big_endian = match (&header[..4], &header[8..]) {
+        (b"RIFF", b"WAVE") => false,
+        (b"FORM", b"AIFF" | b"AIFC") => true,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut tags: Vec<Tag> = Vec::new();
+    let mut chunk = [0u8; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        let id = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        let size_bytes = [chunk[4], chunk[5], chunk[6], chunk[7]];
+        let size = if big_endian { u32::from_be_bytes(size_bytes) } else { u32::from_le_bytes(size_bytes) };
+        // chunks are padded to an even length
+        let padded = size as i64 + (size % 2) as i64;
+        let wanted = match &id {
+            b"id3 " | b"ID3 " => true,
+            b"LIST" => !big_endian,
+            b"NAME" | b"AUTH" => big_endian,
+            _ => false,
+        };
+        if !wanted || size > MAX_TAG_CHUNK {
+            file.seek(SeekFrom::Current(padded))?;
+            continue;
+        }
+        let mut data = vec![0u8; size as usize];
+        // a file cut short loses the tags in its last chunk, not the audio before it
+        if file.read_exact(&mut data).is_err() { break; }
+        file.seek(SeekFrom::Current(padded - size as i64))?;
+
+        match &id {
+            b"LIST" => tags.extend(info_tags(&data)),
+            b"NAME" => tags.push(Tag::new(Some(StandardTagKey::TrackTitle), "NAME", Value::from(text(&data)))),
+            b"AUTH" => tags.push(Tag::new(Some(StandardTagKey::Artist), "AUTH", Value::from(text(&data)))),
+            _ => {
+                let mut builder = MetadataBuilder::new();
+                // a broken tag only costs its own fields
+                if read_id3v2(&mut BytesReader::new(&data), &mut builder).is_ok() {
+                    tags.extend_from_slice(builder.metadata().tags());
+                }
+            }
+        }
+    }
+    Ok(tags)
+}
+
+/// The entries of a `LIST` chunk of type `INFO`, each a little endian sub-chunk holding a string
+fn info_tags(list: &[u8]) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = Vec::new();
+    if list.get(..4) != Some(b"INFO".as_slice()) { return tags; }
+    let mut rest = &list[4..];
+    while rest.len() >= 8 {
+        let id = [rest[0], rest[1], rest[2], rest[3]];
+        let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let Some(data) = rest.get(8..8 + size) else { break };
+        tags.push(riff::parse(id, text(data).as_bytes()));
+        rest = rest.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    tags
+}
+
+/// Chunk strings are usually, but not always, NUL terminated
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).trim_end_matches('\0').trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8], big_endian: bool) -> Vec<u8> {
+        let size = data.len() as u32;
+        let mut chunk = id.to_vec();
+        chunk.extend(if big_endian { size.to_be_bytes() } else { size.to_le_bytes() });
+        chunk.extend(data);
+        if data.len() % 2 == 1 { chunk.push(0); }
+        chunk
+    }
+
+    /// A file of the given form type made of `chunks`, the size in the header isn't looked at
+    fn file(magic: &[u8; 4], form: &[u8; 4], chunks: &[Vec<u8>]) -> Cursor<Vec<u8>> {
+        let mut bytes = magic.to_vec();
+        bytes.extend([0; 4]);
+        bytes.extend(form);
+        bytes.extend(chunks.concat());
+        Cursor::new(bytes)
+    }
+
+    fn info(entries: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut list = b"INFO".to_vec();
+        for (id, data) in entries { list.extend(chunk(id, data, false)); }
+        list
+    }
+
+    fn summary(tags: &[Tag]) -> Vec<(Option<StandardTagKey>, String)> {
+        tags.iter().map(|t| (t.std_key, t.value.to_string())).collect()
+    }
+
+    #[test]
+    fn info_list_after_the_audio() {
+        let list = info(&[(b"INAM", b"Odd\0"), (b"IART", b"Three"), (b"ICRD", b"1999")]);
+        let wav = file(b"RIFF", b"WAVE", &[
+            chunk(b"fmt ", &[0; 16], false),
+            chunk(b"data", &[0; 7], false),
+            chunk(b"LIST", &list, false),
+        ]);
+        assert_eq!(summary(&read_chunk_tags(wav).unwrap()), [
+            (Some(StandardTagKey::TrackTitle), "Odd".to_owned()),
+            (Some(StandardTagKey::Artist), "Three".to_owned()),
+            (Some(StandardTagKey::Date), "1999".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn info_entries_with_odd_lengths() {
+        // "abc" is padded to four bytes, the next entry starts after the pad byte
+        let tags = info_tags(&info(&[(b"INAM", b"abc"), (b"IART", b"de")]));
+        assert_eq!(summary(&tags), [
+            (Some(StandardTagKey::TrackTitle), "abc".to_owned()),
+            (Some(StandardTagKey::Artist), "de".to_owned()),
+        ]);
+        // an entry running past the end of the list ends it
+        let mut cut = info(&[(b"INAM", b"abc"), (b"IART", b"defgh")]);
+        cut.truncate(cut.len() - 3);
+        assert_eq!(info_tags(&cut).len(), 1);
+        assert!(info_tags(b"adtl").is_empty());
+    }
+
+    #[test]
+    fn truncated_chunks() {
+        let list = chunk(b"LIST", &info(&[(b"INAM", b"Kept")]), false);
+        let mut bytes = file(b"RIFF", b"WAVE", &[list, chunk(b"LIST", &info(&[(b"IART", b"Lost")]), false)]).into_inner();
+        bytes.truncate(bytes.len() - 4);
+        assert_eq!(summary(&read_chunk_tags(Cursor::new(bytes)).unwrap()), [
+            (Some(StandardTagKey::TrackTitle), "Kept".to_owned()),
+        ]);
+
+        // a chunk header saying more than the file holds, for a chunk that is skipped over
+        let mut bytes = file(b"RIFF", b"WAVE", &[chunk(b"data", &[0; 8], false)]).into_inner();
+        bytes[16..20].copy_from_slice(&1_000_000u32.to_le_bytes());
+        assert!(read_chunk_tags(Cursor::new(bytes)).unwrap().is_empty());
+        // cut off in the middle of a chunk header
+        assert!(read_chunk_tags(Cursor::new(b"RIFF\0\0\0\0WAVELI".to_vec())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn aiff_text_chunks() {
+        let aiff = file(b"FORM", b"AIFF", &[
+            chunk(b"COMM", &[0; 18], true),
+            chunk(b"NAME", b"Title", true),
+            chunk(b"AUTH", b"Author\0", true),
+            // little endian lists belong to WAV only
+            chunk(b"LIST", &info(&[(b"INAM", b"No")]), true),
+        ]);
+        assert_eq!(summary(&read_chunk_tags(aiff).unwrap()), [
+            (Some(StandardTagKey::TrackTitle), "Title".to_owned()),
+            (Some(StandardTagKey::Artist), "Author".to_owned()),
+        ]);
+        let aifc = file(b"FORM", b"AIFC", &[chunk(b"NAME", b"Compressed", true)]);
+        assert_eq!(read_chunk_tags(aifc).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn other_files_have_no_chunk_tags() {
+        assert!(read_chunk_tags(Cursor::new(b"fLaC\0\0\0\x22and the rest of a flac file".to_vec())).unwrap().is_empty());
+        assert!(read_chunk_tags(Cursor::new(b"RIFF\0\0\0\0AVI LIST".to_vec())).unwrap().is_empty());
+        assert!(read_chunk_tags(Cursor::new(b"ID3".to_vec())).unwrap().is_empty());
+        // NAME chunks mean nothing in a WAV
+        let wav = file(b"RIFF", b"WAVE", &[chunk(b"NAME", b"Title", false)]);
+        assert!(read_chunk_tags(wav).unwrap().is_empty());
+    }
+}
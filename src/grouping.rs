@@ -0,0 +1,299 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::playlist::Song;
+
+const UNKNOWN_ARTIST: &str = "Unknown Artist";
+const UNKNOWN_ALBUM: &str = "Unknown Album";
+const UNKNOWN_GENRE: &str = "Unknown Genre";
+const UNKNOWN_YEAR: &str = "Unknown Year";
+/// where compilations are filed when grouping by album artist
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+/// what taggers write for "various artists", compared ignoring case
+const VARIOUS_ARTISTS_NAMES: [&str; 3] = ["various artists", "various", "va"];
+/// starts the identifiers of unknown groups, with a character no tag contains so the songs missing an artist tag
+/// don't end up next to a node for an artist tagged "Unknown Artist" under the same identifier
+const UNKNOWN_ID_PREFIX: &str = "\u{0}unknown:";
+/// what separates the main artist from guests in a track's artist credit
+const FEATURING: [&str; 4] = [" feat. ", " feat ", " ft. ", " featuring "];
+
+/// One node on the way from the root of the library tree down to a song
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Group {
+    /// shown in the tree
+    pub label: String,
+    pub kind: GroupKind,
+    /// the song has no tag for this level, these groups go after all others
+    pub unknown: bool,
+}
+
+/// What a group stands for, which decides how groups on the same level are ordered
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GroupKind {
+    /// an artist, genre, ...: by name ignoring case and articles
+    Name,
+    /// an album: by name, or by year first when sorting by artist
+    Album,
+    /// a decade or a year: by its number
+    Number(i32),
+    /// a directory: by name as it is
+    Folder,
+}
+
+impl Group {
+    /// The group's identifier in the tree, which no other group on the same level shares
+    pub fn id(&self) -> String {
+        if self.unknown { format!("{}{}", UNKNOWN_ID_PREFIX, self.label) } else { self.label.clone() }
+    }
+
+    fn name(label: Option<&str>, unknown_label: &str) -> Self {
+        Self { label: label.unwrap_or(unknown_label).to_owned(), kind: GroupKind::Name, unknown: label.is_none() }
+    }
+
+    fn album(song: &Song) -> Self {
+        Self {
+            label: song.album.as_deref().unwrap_or(UNKNOWN_ALBUM).to_owned(),
+            kind: GroupKind::Album,
+            unknown: song.album.is_none(),
+        }
+    }
+
+    fn number(label: String, number: i32) -> Self {
+        Self { label, kind: GroupKind::Number(number), unknown: false }
+    }
+
+    fn folder(label: String) -> Self {
+        Self { label, kind: GroupKind::Folder, unknown: false }
+    }
+}
+
+/// A way of arranging the library into a tree. The tree puts every song below the groups it gets from
+/// [`Grouping::groups`]; songs sharing a group share the node, the library takes care of ordering them
+pub trait Grouping {
+    /// The groups above `song`, outermost first. Songs may end up at different depths
+    fn groups(&self, song: &Song, roots: &[PathBuf]) -> Vec<Group>;
+
+    /// Whether songs within their group are ordered by file name rather than by disc and track number
+    fn by_file_name(&self) -> bool {
+        false
+    }
+}
+
+/// Artist -> album, by the artist credited on each track
+pub struct ByArtist;
+
+impl Grouping for ByArtist {
+    fn groups(&self, song: &Song, _: &[PathBuf]) -> Vec<Group> {
+        vec![Group::name(song.artist.as_deref(), UNKNOWN_ARTIST), Group::album(song)]
+    }
+}
+
+/// Album artist -> album, so an album shows up once whoever is credited on its tracks
+pub struct ByAlbumArtist;
+
+impl Grouping for ByAlbumArtist {
+    fn groups(&self, song: &Song, _: &[PathBuf]) -> Vec<Group> {
+        vec![Group::name(album_artist_of(song), UNKNOWN_ARTIST), Group::album(song)]
+    }
+}
+
+/// Genre -> album artist -> album
+pub struct ByGenre;
+
+impl Grouping for ByGenre {
+    fn groups(&self, song: &Song, _: &[PathBuf]) -> Vec<Group> {
+        vec![
+            Group::name(song.genre.as_deref(), UNKNOWN_GENRE),
+            Group::name(album_artist_of(song), UNKNOWN_ARTIST),
+            Group::album(song),
+        ]
+    }
+}
+
+/// Who the album `song` is on belongs to: the album artist tag, "Various Artists" for compilations without one,
+/// otherwise the track's artist without any guests ("X feat. Y" is on an album by X)
+pub fn album_artist_of(song: &Song) -> Option<&str> {
+    match song.album_artist.as_deref() {
+        Some(name) if VARIOUS_ARTISTS_NAMES.contains(&name.to_lowercase().as_str()) => Some(VARIOUS_ARTISTS),
+        Some(name) => Some(name),
+        None if song.compilation => Some(VARIOUS_ARTISTS),
+        None => song.artist.as_deref().map(primary_artist),
+    }
+}
+
+/// The main artist of a credit like "X feat. Y"
+pub fn primary_artist(artist: &str) -> &str {
+    // the separators are ASCII, and ASCII lowercasing keeps every other character where it was in the original
+    let lower = artist.to_ascii_lowercase();
+    let end = FEATURING.iter().filter_map(|f| lower.find(f)).min().unwrap_or(artist.len());
+    artist[..end].trim()
+}
+
+/// Decade -> year -> album
+pub struct ByYear;
+
+impl Grouping for ByYear {
+    fn groups(&self, song: &Song, _: &[PathBuf]) -> Vec<Group> {
+        match song.year {
+            Some(year) => {
+                let decade = year.div_euclid(10) * 10;
+                vec![Group::number(format!("{}s", decade), decade), Group::number(year.to_string(), year), Group::album(song)]
+            }
+            None => vec![Group::name(None, UNKNOWN_YEAR), Group::album(song)],
+        }
+    }
+}
+
+/// The directories the files are in, below the library root they were found in. With more than one root the
+/// roots themselves are the top level
+pub struct ByFolder;
+
+impl Grouping for ByFolder {
+    fn groups(&self, song: &Song, roots: &[PathBuf]) -> Vec<Group> {
+        let path = Path::new(&song.path);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let root = roots.iter().find(|r| dir.starts_with(r));
+        let mut groups: Vec<Group> = Vec::new();
+        if let (Some(root), true) = (root, roots.len() > 1) {
+            groups.push(Group::folder(root.display().to_string()));
+        }
+        let relative = root.and_then(|r| dir.strip_prefix(r).ok()).unwrap_or(dir);
+        groups.extend(relative.iter().map(|c| Group::folder(c.to_string_lossy().into_owned())));
+        groups
+    }
+
+    fn by_file_name(&self) -> bool {
+        true
+    }
+}
+
+/// The views the library tree can be switched between
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum View {
+    #[default]
+    AlbumArtist,
+    Artist,
+    Genre,
+    Year,
+    Folder,
+}
+
+impl View {
+    pub fn grouping(self) -> &'static dyn Grouping {
+        match self {
+            View::Artist => &ByArtist,
+            View::AlbumArtist => &ByAlbumArtist,
+            View::Genre => &ByGenre,
+            View::Year => &ByYear,
+            View::Folder => &ByFolder,
+        }
+    }
+
+    pub fn cycle(self) -> Self {
+        match self {
+            View::AlbumArtist => View::Artist,
+            View::Artist => View::Genre,
+            View::Genre => View::Year,
+            View::Year => View::Folder,
+            View::Folder => View::AlbumArtist,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            View::Artist => "artist",
+            View::AlbumArtist => "album artist",
+            View::Genre => "genre",
+            View::Year => "year",
+            View::Folder => "folder",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(groups: &[Group]) -> Vec<&str> {
+        groups.iter().map(|g| g.label.as_str()).collect()
+    }
+
+    #[test]
+    fn primary_artists() {
+        assert_eq!(primary_artist("Daft Punk"), "Daft Punk");
+        assert_eq!(primary_artist("Daft Punk feat. Pharrell Williams"), "Daft Punk");
+        assert_eq!(primary_artist("A FT. B"), "A");
+        assert_eq!(primary_artist("A featuring B feat. C"), "A");
+        // lowercasing these changes their length in bytes
+        assert_eq!(primary_artist("İİ feat. X"), "İİ");
+        assert_eq!(primary_artist("ẞẞ Feat X"), "ẞẞ");
+        assert_eq!(primary_artist("\u{212a}elvin ft. Y"), "\u{212a}elvin");
+        assert_eq!(primary_artist("Beyoncé feat. Jay-Z"), "Beyoncé");
+        // only whole words
+        assert_eq!(primary_artist("Featherstone"), "Featherstone");
+    }
+
+    #[test]
+    fn album_artists() {
+        let mut song = Song::untagged("/music/a.mp3");
+        song.artist = Some("Sigur Rós ft. X".to_owned());
+        assert_eq!(album_artist_of(&song), Some("Sigur Rós"));
+        song.compilation = true;
+        assert_eq!(album_artist_of(&song), Some(VARIOUS_ARTISTS));
+        // an album artist tag wins over the compilation flag
+        song.album_artist = Some("Björk".to_owned());
+        assert_eq!(album_artist_of(&song), Some("Björk"));
+        for name in ["Various Artists", "VARIOUS", "va", "Va"] {
+            song.album_artist = Some(name.to_owned());
+            assert_eq!(album_artist_of(&song), Some(VARIOUS_ARTISTS), "{:?}", name);
+        }
+        song.album_artist = Some("Various Artists feat. Nobody".to_owned());
+        assert_eq!(album_artist_of(&song), Some("Various Artists feat. Nobody"));
+
+        let untagged = Song::untagged("/music/b.mp3");
+        assert_eq!(album_artist_of(&untagged), None);
+    }
+
+    #[test]
+    fn by_year() {
+        let mut song = Song::untagged("/music/a.mp3");
+        song.album = Some("Album".to_owned());
+        song.year = Some(1997);
+        assert_eq!(labels(&ByYear.groups(&song, &[])), ["1990s", "1997", "Album"]);
+        song.year = Some(-5);
+        assert_eq!(labels(&ByYear.groups(&song, &[])), ["-10s", "-5", "Album"]);
+
+        song.year = None;
+        let groups = ByYear.groups(&song, &[]);
+        assert_eq!(labels(&groups), ["Unknown Year", "Album"]);
+        assert!(groups[0].unknown && !groups[1].unknown);
+    }
+
+    #[test]
+    fn by_folder() {
+        let song = Song::untagged("/music/Artist/Album/01.flac");
+        let roots = [PathBuf::from("/other"), PathBuf::from("/music")];
+        assert_eq!(labels(&ByFolder.groups(&song, &roots[1..])), ["Artist", "Album"]);
+        // with several roots, the root a song was found in comes first
+        assert_eq!(labels(&ByFolder.groups(&song, &roots)), ["/music", "Artist", "Album"]);
+        assert!(labels(&ByFolder.groups(&Song::untagged("/music/loose.mp3"), &roots[1..])).is_empty());
+        assert!(ByFolder.groups(&song, &roots).iter().all(|g| g.kind == GroupKind::Folder && !g.unknown));
+    }
+
+    #[test]
+    fn unknown_ids() {
+        let unknown = Group::name(None, UNKNOWN_ARTIST);
+        let named = Group::name(Some(UNKNOWN_ARTIST), UNKNOWN_ARTIST);
+        assert_eq!(unknown.label, named.label);
+        assert_ne!(unknown.id(), named.id());
+        assert_eq!(named.id(), UNKNOWN_ARTIST);
+
+        let mut song = Song::untagged("/music/a.mp3");
+        song.album = Some(UNKNOWN_ALBUM.to_owned());
+        let [_, tagged]: [Group; 2] = ByArtist.groups(&song, &[]).try_into().unwrap();
+        song.album = None;
+        let [_, untagged]: [Group; 2] = ByArtist.groups(&song, &[]).try_into().unwrap();
+        assert_ne!(tagged.id(), untagged.id());
+    }
+}
@@ -1,15 +1,22 @@
+use image::DynamicImage;
+use ratatui::{
+    style::{Color, Style},
+    text::{Line, Span, Text},
+};
 use rascii_art::{
-    render_to,
+    render_image_to,
     RenderOptions,
 };
 
-pub fn to_ascii(file: String, size: u32) -> String {
+/// Renders `image` as coloured ASCII art exactly `width` x `height` characters big
+pub fn to_ascii(image: &DynamicImage, width: u32, height: u32) -> String {
     let mut buffer: String = String::new();
-    render_to(
-        file,
+    render_image_to(
+        image,
         &mut buffer,
         &RenderOptions::new()
-            .width(size)
+            .width(width)
+            .height(height)
             .colored(true)
             // .charset(&[".", "+","?","Q","#","@", "~"]),
             .charset(&["#"]),
@@ -17,5 +24,91 @@ pub fn to_ascii(file: String, size: u32) -> String {
 			// .charset(&["$","@","B","%","8","&","W","M","#","*","o","a","h","k","b","d","p","q","w","m","Z","O","0","Q","L","C","J","U","Y","X","z","c","v","u","n","x","r","j","f","t","|","1","?","-","_","+","~","<","i","!","l","I",";",":",",","^","`","'","."])
 			// .charset(&["$","@","B","8","W","M","*","o","a","h","k","b","d","p","q","w","m","Z","O","0","Q","L","C","J","U","Y","X","z","c","v","u","n","x","r","j","f","t","|","1","?","-","_","+","~","<","i","!","l","I",";",":",",","^","`","'","."])
     ).unwrap_or_default();
-	return buffer;
-}
\ No newline at end of file
+	buffer
+}
+
+/// Renders `image` as large as it fits into `width` x `height` terminal cells while keeping its aspect ratio,
+/// ready to be drawn by ratatui
+pub fn to_ascii_text(image: &DynamicImage, width: u16, height: u16) -> Text<'static> {
+	if width == 0 || height == 0 || image.width() == 0 || image.height() == 0 { return Text::default(); }
+	// terminal cells are about twice as high as they are wide
+	let aspect = image.width() as f64 / image.height() as f64 * 2.;
+	let (w, h) = if width as f64 / height as f64 > aspect {
+		((height as f64 * aspect).round().max(1.) as u32, height as u32)
+	} else {
+		(width as u32, (width as f64 / aspect).round().max(1.) as u32)
+	};
+	ansi_to_text(&to_ascii(image, w, h))
+}
+
+/// Turns the renderer's output into styled spans. It only ever emits 24 bit foreground colours
+/// (`ESC[38;2;r;g;bm`) and resets (`ESC[0m`), so those are the only sequences understood here
+fn ansi_to_text(ansi: &str) -> Text<'static> {
+	let mut lines: Vec<Line<'static>> = Vec::new();
+	for raw_line in ansi.lines() {
+		let mut spans: Vec<Span<'static>> = Vec::new();
+		let mut style = Style::new();
+		let mut rest = raw_line;
+		while let Some(start) = rest.find('\x1b') {
+			if start > 0 { spans.push(Span::styled(rest[..start].to_owned(), style)); }
+			let sequence = &rest[start..];
+			// a sequence cut off at the end of the line is dropped with the rest of it
+			let Some(end) = sequence.find('m') else { rest = ""; break };
+			// anything that isn't `ESC[...m` resets, the renderer isn't meant to emit it
+			let codes: Vec<u8> = sequence.get(2..end)
+				.filter(|_| sequence[1..].starts_with('['))
+				.map(|params| params.split(';').filter_map(|c| c.parse().ok()).collect())
+				.unwrap_or_default();
+			style = match codes.as_slice() {
+				[38, 2, r, g, b] => style.fg(Color::Rgb(*r, *g, *b)),
+				_ => Style::new(),
+			};
+			rest = &sequence[end + 1..];
+		}
+		if !rest.is_empty() { spans.push(Span::styled(rest.to_owned(), style)); }
+		lines.push(Line::from(spans));
+	}
+	Text::from(lines)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn spans(ansi: &str) -> Vec<Vec<(String, Style)>> {
+		ansi_to_text(ansi).lines.iter()
+			.map(|line| line.spans.iter().map(|s| (s.content.to_string(), s.style)).collect())
+			.collect()
+	}
+
+	fn plain(text: &str) -> (String, Style) {
+		(text.to_owned(), Style::new())
+	}
+
+	#[test]
+	fn plain_text() {
+		assert_eq!(spans("##\n#"), [vec![plain("##")], vec![plain("#")]]);
+		assert!(spans("").is_empty());
+	}
+
+	#[test]
+	fn truecolor_and_reset() {
+		let red = Style::new().fg(Color::Rgb(255, 0, 0));
+		assert_eq!(spans("\x1b[38;2;255;0;0m##\x1b[0m#"), [vec![("##".to_owned(), red), plain("#")]]);
+		// every line starts out plain
+		let colored = ("#".to_owned(), Style::new().fg(Color::Rgb(1, 2, 3)));
+		assert_eq!(spans("\x1b[38;2;1;2;3m#\n#"), [vec![colored], vec![plain("#")]]);
+	}
+
+	#[test]
+	fn malformed_sequences() {
+		assert_eq!(spans("a\x1bmb"), [vec![plain("a"), plain("b")]]);
+		assert_eq!(spans("\x1b(m#"), [vec![plain("#")]]);
+		assert_eq!(spans("\x1b[38;2;300;0;0m#"), [vec![plain("#")]]);
+		assert_eq!(spans("\x1b[1;31m#"), [vec![plain("#")]]);
+		assert_eq!(spans("\x1bé[m#"), [vec![plain("#")]]);
+		// cut off
+		assert_eq!(spans("#\x1b[38;2;1"), [vec![plain("#")]]);
+		assert_eq!(spans("#\x1b"), [vec![plain("#")]]);
+	}
+}
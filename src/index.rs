@@ -0,0 +1,159 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, BufReader, BufWriter, Write}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::playlist::{Song, SongError};
+
+/// Bumped whenever the on-disk layout (including the fields of [`Song`]) changes
+const INDEX_VERSION: u32 = 5;
+
+/// The file size and modification time an index entry was probed at; a file whose stamp differs is probed again
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified_secs: u64,
+    pub modified_nanos: u32,
+}
+
+impl FileStamp {
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        let modified = metadata.modified().ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self {
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    stamp: FileStamp,
+    song: Song,
+    /// when the file was first seen by a scan, in seconds since the epoch
+    added: u64,
+}
+
+/// On-disk cache of probed songs keyed by path, so a launch only has to probe files that are new or changed.
+/// Files that failed to probe are not cached and get retried on the next scan
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LibraryIndex {
+    version: u32,
+    entries: HashMap<String, IndexEntry>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl LibraryIndex {
+    /// An index that lives only in memory, used when there is nowhere to save it
+    pub fn in_memory() -> Self {
+        Self {
+            version: INDEX_VERSION,
+            entries: HashMap::new(),
+            path: None,
+            dirty: false,
+        }
+    }
+
+    /// Loads the index stored at `path`. A missing, unreadable or outdated index starts over empty, it's only a
+    /// cache after all
+    pub fn load(path: PathBuf) -> Self {
+        let stored: Option<LibraryIndex> = File::open(&path).ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .and_then(Self::migrate);
+        let mut index = stored.unwrap_or_else(Self::in_memory);
+        index.path = Some(path);
+        index
+    }
+
+    /// Brings an index written by an older version up to date, or drops it if there's no way to
+    fn migrate(index: LibraryIndex) -> Option<LibraryIndex> {
+        match index.version {
+            INDEX_VERSION => Some(index),
+            // no older layouts worth converting yet, they get rebuilt from the files
+            _ => None,
+        }
+    }
+
+    /// Returns the song at `path`, probing the file only if it isn't indexed at the current `stamp`
+    pub fn get_or_probe(&mut self, path: &str, stamp: FileStamp) -> Result<Song, SongError> {
+        if let Some(entry) = self.entries.get(path).filter(|e| e.stamp == stamp) {
+            return Ok(entry.song.clone());
+        }
+        let song = Song::new(path.to_owned());
+        match &song {
+            Ok(song) => {
+                // a file that changed keeps the time it was first added
+                let added = self.entries.get(path).map_or_else(now_secs, |e| e.added);
+                self.entries.insert(path.to_owned(), IndexEntry { stamp, song: song.clone(), added });
+            }
+            Err(_) => { self.entries.remove(path); }
+        }
+        self.dirty = true;
+        song
+    }
+
+    /// When the file at `path` was first seen by a scan, in seconds since the epoch
+    pub fn added(&self, path: &str) -> Option<u64> {
+        self.entries.get(path).map(|e| e.added)
+    }
+
+    /// Drops every entry whose file wasn't seen in the last scan
+    pub fn retain(&mut self, seen: &HashSet<String>) {
+        let before = self.entries.len();
+        self.entries.retain(|path, _| seen.contains(path));
+        self.dirty |= self.entries.len() != before;
+    }
+
+    /// Writes the index back if anything changed since it was loaded
+    pub fn save(&mut self) -> std::io::Result<()> {
+        let Some(path) = self.path.as_deref().filter(|_| self.dirty) else { return Ok(()) };
+        write_atomically(path, |writer| Ok(serde_json::to_writer(writer, self)?))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Has `write` fill a temporary file next to `path` and only then puts it in place, so a crash mid-write can't
+/// leave half a file behind. Missing parent directories are created
+pub fn write_atomically(path: &Path, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+    if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_writes() {
+        let dir = std::env::temp_dir().join(format!("horizon-atomic-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("nested/file.json");
+        write_atomically(&path, |writer| writer.write_all(b"first")).unwrap();
+        write_atomically(&path, |writer| writer.write_all(b"second")).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!path.with_extension("tmp").exists());
+
+        // a failed write leaves the old file alone
+        let failed = write_atomically(&path, |writer| {
+            writer.write_all(b"half")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
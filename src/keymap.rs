@@ -0,0 +1,649 @@
+use std::{collections::HashMap, fmt};
+
+use color_eyre::{eyre::bail, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+
+/// Where a binding applies: everywhere, or only while one of the panes has focus. Global bindings are looked at
+/// first, so a pane can't rebind a key that is taken globally
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Context {
+    Global,
+    Queue,
+    Tree,
+    Player,
+}
+
+impl Context {
+    pub const ALL: [Context; 4] = [Context::Global, Context::Queue, Context::Tree, Context::Player];
+
+    /// The table of `[keys]` in the config file this context is set up in
+    pub fn name(self) -> &'static str {
+        match self {
+            Context::Global => "global",
+            Context::Queue => "queue",
+            Context::Tree => "tree",
+            Context::Player => "player",
+        }
+    }
+}
+
+/// Everything a key can be bound to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Quit,
+    Help,
+    FocusTree,
+    FocusPlayer,
+    FocusQueue,
+    Search,
+    Next,
+    Previous,
+    CycleRepeat,
+    ToggleShuffle,
+    VolumeDown,
+    VolumeUp,
+    VolumeDownFine,
+    VolumeUpFine,
+    ToggleMute,
+    TogglePause,
+    Up,
+    Down,
+    First,
+    Last,
+    /// queue: play the selected entry
+    Play,
+    MoveUp,
+    MoveDown,
+    Deselect,
+    Mark,
+    Remove,
+    Cut,
+    Copy,
+    Paste,
+    PlayNext,
+    Clear,
+    Undo,
+    SavePlaylist,
+    Details,
+    /// tree: open the selected node
+    Expand,
+    Collapse,
+    Enqueue,
+    EnqueueNext,
+    ReplaceQueue,
+    CycleSort,
+    CycleView,
+    SeekBack,
+    SeekForward,
+    SeekBackLong,
+    SeekForwardLong,
+    /// jump to this many tenths into the track
+    SeekTenths(u8),
+    SeekPrompt,
+}
+
+impl Action {
+    /// How the action is called in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::Help => "help",
+            Action::FocusTree => "focus_tree",
+            Action::FocusPlayer => "focus_player",
+            Action::FocusQueue => "focus_queue",
+            Action::Search => "search",
+            Action::Next => "next",
+            Action::Previous => "previous",
+            Action::CycleRepeat => "cycle_repeat",
+            Action::ToggleShuffle => "toggle_shuffle",
+            Action::VolumeDown => "volume_down",
+            Action::VolumeUp => "volume_up",
+            Action::VolumeDownFine => "volume_down_fine",
+            Action::VolumeUpFine => "volume_up_fine",
+            Action::ToggleMute => "toggle_mute",
+            Action::TogglePause => "toggle_pause",
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::First => "first",
+            Action::Last => "last",
+            Action::Play => "play",
+            Action::MoveUp => "move_up",
+            Action::MoveDown => "move_down",
+            Action::Deselect => "deselect",
+            Action::Mark => "mark",
+            Action::Remove => "remove",
+            Action::Cut => "cut",
+            Action::Copy => "copy",
+            Action::Paste => "paste",
+            Action::PlayNext => "play_next",
+            Action::Clear => "clear",
+            Action::Undo => "undo",
+            Action::SavePlaylist => "save_playlist",
+            Action::Details => "details",
+            Action::Expand => "expand",
+            Action::Collapse => "collapse",
+            Action::Enqueue => "enqueue",
+            Action::EnqueueNext => "enqueue_next",
+            Action::ReplaceQueue => "replace_queue",
+            Action::CycleSort => "cycle_sort",
+            Action::CycleView => "cycle_view",
+            Action::SeekBack => "seek_back",
+            Action::SeekForward => "seek_forward",
+            Action::SeekBackLong => "seek_back_long",
+            Action::SeekForwardLong => "seek_forward_long",
+            Action::SeekTenths(0) => "seek_0",
+            Action::SeekTenths(1) => "seek_10",
+            Action::SeekTenths(2) => "seek_20",
+            Action::SeekTenths(3) => "seek_30",
+            Action::SeekTenths(4) => "seek_40",
+            Action::SeekTenths(5) => "seek_50",
+            Action::SeekTenths(6) => "seek_60",
+            Action::SeekTenths(7) => "seek_70",
+            Action::SeekTenths(8) => "seek_80",
+            Action::SeekTenths(_) => "seek_90",
+            Action::SeekPrompt => "seek_prompt",
+        }
+    }
+
+    /// What the action does, as listed in the help overlay
+    pub fn description(self) -> String {
+        match self {
+            Action::Quit => "Quit",
+            Action::Help => "Show the keys for the focused pane",
+            Action::FocusTree => "Focus the library tree",
+            Action::FocusPlayer => "Focus the player",
+            Action::FocusQueue => "Focus the queue",
+            Action::Search => "Search the library",
+            Action::Next => "Skip to the next track",
+            Action::Previous => "Back to the previous track, or restart this one",
+            Action::CycleRepeat => "Repeat off / all / one",
+            Action::ToggleShuffle => "Shuffle on / off",
+            Action::VolumeDown => "Volume down",
+            Action::VolumeUp => "Volume up",
+            Action::VolumeDownFine => "Volume down a little",
+            Action::VolumeUpFine => "Volume up a little",
+            Action::ToggleMute => "Mute / unmute",
+            Action::TogglePause => "Pause / resume",
+            Action::Up => "Select the previous entry",
+            Action::Down => "Select the next entry",
+            Action::First => "Select the first entry",
+            Action::Last => "Select the last entry",
+            Action::Play => "Play the selected track",
+            Action::MoveUp => "Move the selection up",
+            Action::MoveDown => "Move the selection down",
+            Action::Deselect => "Drop the range, then the selection",
+            Action::Mark => "Start / end a range at the selection",
+            Action::Remove => "Remove the selection",
+            Action::Cut => "Cut the selection",
+            Action::Copy => "Copy the selection",
+            Action::Paste => "Paste after the selection",
+            Action::PlayNext => "Move the selection after the current track",
+            Action::Clear => "Clear the queue",
+            Action::Undo => "Undo the last change to the queue",
+            Action::SavePlaylist => "Save the queue as a playlist",
+            Action::Details => "Show everything about the selected track",
+            Action::Expand => "Open the selected node",
+            Action::Collapse => "Close the selected node",
+            Action::Enqueue => "Add the selection to the queue",
+            Action::EnqueueNext => "Play the selection next",
+            Action::ReplaceQueue => "Replace the queue with the selection",
+            Action::CycleSort => "Change the order",
+            Action::CycleView => "Change the grouping",
+            Action::SeekBack => "Back 5 seconds",
+            Action::SeekForward => "Forward 5 seconds",
+            Action::SeekBackLong => "Back 30 seconds",
+            Action::SeekForwardLong => "Forward 30 seconds",
+            Action::SeekTenths(tenths) => return format!("Jump to {}%", tenths as u32 * 10),
+            Action::SeekPrompt => "Go to a time or percentage",
+        }.to_owned()
+    }
+}
+
+/// The bindings used for whatever the config file doesn't set. An action can only be bound in the contexts it
+/// appears in here
+const DEFAULTS: &[(Context, Action, &[&str])] = &[
+    (Context::Global, Action::Quit, &["alt+q"]),
+    (Context::Global, Action::Help, &["?"]),
+    (Context::Global, Action::FocusTree, &["t"]),
+    (Context::Global, Action::FocusPlayer, &["p"]),
+    (Context::Global, Action::FocusQueue, &["q"]),
+    (Context::Global, Action::Search, &["/"]),
+    (Context::Global, Action::Next, &["shift+n"]),
+    (Context::Global, Action::Previous, &["shift+p"]),
+    (Context::Global, Action::CycleRepeat, &["shift+r"]),
+    (Context::Global, Action::ToggleShuffle, &["shift+s"]),
+    (Context::Global, Action::VolumeDown, &[","]),
+    (Context::Global, Action::VolumeUp, &["."]),
+    (Context::Global, Action::VolumeDownFine, &["alt+,"]),
+    (Context::Global, Action::VolumeUpFine, &["alt+."]),
+    (Context::Global, Action::ToggleMute, &["m"]),
+    (Context::Queue, Action::Play, &["enter"]),
+    (Context::Queue, Action::TogglePause, &["space"]),
+    (Context::Queue, Action::Up, &["up"]),
+    (Context::Queue, Action::Down, &["down"]),
+    (Context::Queue, Action::First, &["g g", "home"]),
+    (Context::Queue, Action::Last, &["shift+g", "end"]),
+    (Context::Queue, Action::MoveUp, &["shift+up"]),
+    (Context::Queue, Action::MoveDown, &["shift+down"]),
+    (Context::Queue, Action::Deselect, &["esc"]),
+    (Context::Queue, Action::Mark, &["s"]),
+    (Context::Queue, Action::Remove, &["d", "delete"]),
+    (Context::Queue, Action::Cut, &["x"]),
+    (Context::Queue, Action::Copy, &["c"]),
+    (Context::Queue, Action::Paste, &["v"]),
+    (Context::Queue, Action::PlayNext, &["n"]),
+    (Context::Queue, Action::Clear, &["shift+c"]),
+    (Context::Queue, Action::Undo, &["u"]),
+    (Context::Queue, Action::SavePlaylist, &["w"]),
+    (Context::Queue, Action::Details, &["i"]),
+    (Context::Tree, Action::Up, &["up"]),
+    (Context::Tree, Action::Down, &["down"]),
+    (Context::Tree, Action::First, &["g g", "home"]),
+    (Context::Tree, Action::Last, &["shift+g", "end"]),
+    (Context::Tree, Action::Expand, &["right"]),
+    (Context::Tree, Action::Collapse, &["left"]),
+    (Context::Tree, Action::Enqueue, &["enter"]),
+    (Context::Tree, Action::EnqueueNext, &["n"]),
+    (Context::Tree, Action::ReplaceQueue, &["r"]),
+    (Context::Tree, Action::CycleSort, &["o"]),
+    (Context::Tree, Action::CycleView, &["v"]),
+    (Context::Player, Action::TogglePause, &["space"]),
+    (Context::Player, Action::SeekBack, &["left"]),
+    (Context::Player, Action::SeekForward, &["right"]),
+    (Context::Player, Action::SeekBackLong, &["shift+left"]),
+    (Context::Player, Action::SeekForwardLong, &["shift+right"]),
+    (Context::Player, Action::SeekTenths(0), &["0"]),
+    (Context::Player, Action::SeekTenths(1), &["1"]),
+    (Context::Player, Action::SeekTenths(2), &["2"]),
+    (Context::Player, Action::SeekTenths(3), &["3"]),
+    (Context::Player, Action::SeekTenths(4), &["4"]),
+    (Context::Player, Action::SeekTenths(5), &["5"]),
+    (Context::Player, Action::SeekTenths(6), &["6"]),
+    (Context::Player, Action::SeekTenths(7), &["7"]),
+    (Context::Player, Action::SeekTenths(8), &["8"]),
+    (Context::Player, Action::SeekTenths(9), &["9"]),
+    (Context::Player, Action::SeekPrompt, &["g"]),
+];
+
+/// One key press with its modifiers. Shift is folded into the character for character keys, so "shift+n" and "N"
+/// are the same chord, however the terminal reports them
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct KeyChord {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let relevant = KeyModifiers::SHIFT | KeyModifiers::CONTROL | KeyModifiers::ALT;
+        match code {
+            KeyCode::Char(c) => {
+                let c = if modifiers.contains(KeyModifiers::SHIFT) { c.to_uppercase().next().unwrap_or(c) } else { c };
+                Self { code: KeyCode::Char(c), modifiers: modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT) }
+            }
+            KeyCode::BackTab => Self { code, modifiers: modifiers & relevant & !KeyModifiers::SHIFT },
+            _ => Self { code, modifiers: modifiers & relevant },
+        }
+    }
+
+    /// Reads chords like "alt+q", "shift+left", "N", "space" or "ctrl++", or tells what is wrong with them
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = text;
+        // what comes after the last separating "+" is the key, which may be "+" itself
+        while let Some((modifier, key)) = rest.split_once('+').filter(|(m, k)| !m.is_empty() && !k.is_empty()) {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("there is no {:?} modifier", modifier)),
+            };
+            rest = key;
+        }
+        let code = match rest.to_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" | "ins" => KeyCode::Insert,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            lower => match (lower.strip_prefix('f').and_then(|n| n.parse().ok()), single_char(rest)) {
+                (Some(n @ 1..=12), _) => KeyCode::F(n),
+                (_, Some(c)) => KeyCode::Char(c),
+                _ => return Err(format!("there is no {:?} key", rest)),
+            },
+        };
+        // terminals send shift+1 as "!", a chord folding it into "1" would never come
+        if let (KeyCode::Char(c), true) = (code, modifiers.contains(KeyModifiers::SHIFT)) {
+            if c.to_uppercase().eq(c.to_lowercase()) {
+                return Err(format!("shift only goes with letters, write the character shift+{} types instead", c));
+            }
+        }
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+impl From<KeyEvent> for KeyChord {
+    fn from(key: KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) { write!(f, "ctrl+")?; }
+        if self.modifiers.contains(KeyModifiers::ALT) { write!(f, "alt+")?; }
+        if self.modifiers.contains(KeyModifiers::SHIFT) { write!(f, "shift+")?; }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::BackTab => write!(f, "shift+tab"),
+            KeyCode::F(n) => write!(f, "f{}", n),
+            KeyCode::PageUp => write!(f, "pageup"),
+            KeyCode::PageDown => write!(f, "pagedown"),
+            other => write!(f, "{}", format!("{:?}", other).to_lowercase()),
+        }
+    }
+}
+
+/// A key, or keys pressed one after another like `g g`, and what they do where
+#[derive(Clone, Debug)]
+pub struct Binding {
+    pub context: Context,
+    pub keys: Vec<KeyChord>,
+    pub action: Action,
+}
+
+impl Binding {
+    /// The keys as written in the config file
+    pub fn keys_label(&self) -> String {
+        self.keys.iter().map(KeyChord::to_string).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// The `[keys]` part of the config file: a table per context mapping action names to a key or a list of keys.
+/// Setting an action replaces all of its default keys in that context, an empty list unbinds it
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    pub global: HashMap<String, Keys>,
+    pub queue: HashMap<String, Keys>,
+    pub tree: HashMap<String, Keys>,
+    pub player: HashMap<String, Keys>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Keys {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeysConfig {
+    fn context(&self, context: Context) -> &HashMap<String, Keys> {
+        match context {
+            Context::Global => &self.global,
+            Context::Queue => &self.queue,
+            Context::Tree => &self.tree,
+            Context::Player => &self.player,
+        }
+    }
+}
+
+/// Which keys do what, in which pane
+#[derive(Clone)]
+pub struct Keymap {
+    pub bindings: Vec<Binding>,
+}
+
+impl Keymap {
+    /// The default bindings with the config file's applied over them. Unknown actions, unreadable keys and keys
+    /// that would be shadowed by another binding are all reported at once
+    pub fn new(config: &KeysConfig) -> Result<Self> {
+        let mut bindings: Vec<Binding> = Vec::new();
+        let mut errors: Vec<String> = Vec::new();
+        for context in Context::ALL {
+            let overrides = config.context(context);
+            let mut names: Vec<&String> = overrides.keys().collect();
+            names.sort();
+            for name in names {
+                if !DEFAULTS.iter().any(|(c, a, _)| *c == context && a.name() == name) {
+                    errors.push(format!("[keys.{}]: there is no {} action here", context.name(), name));
+                }
+            }
+            for (_, action, default_keys) in DEFAULTS.iter().filter(|(c, _, _)| *c == context) {
+                let keys: Vec<&str> = match overrides.get(action.name()) {
+                    Some(Keys::One(key)) => vec![key.as_str()],
+                    Some(Keys::Many(keys)) => keys.iter().map(String::as_str).collect(),
+                    None => default_keys.to_vec(),
+                };
+                for text in keys {
+                    let chords = match text.split_whitespace().map(KeyChord::parse).collect::<Result<Vec<KeyChord>, String>>() {
+                        Ok(chords) if chords.is_empty() => Err("no key given".to_owned()),
+                        other => other,
+                    };
+                    match chords {
+                        Ok(chords) => bindings.push(Binding { context, keys: chords, action: *action }),
+                        Err(reason) => errors.push(format!(
+                            "[keys.{}] {}: can't read the key {:?}, {}", context.name(), action.name(), text, reason,
+                        )),
+                    }
+                }
+            }
+        }
+
+        let keymap = Self { bindings };
+        errors.extend(keymap.conflicts());
+        if !errors.is_empty() { bail!("invalid key bindings:\n  {}", errors.join("\n  ")); }
+        Ok(keymap)
+    }
+
+    /// Pairs of bindings where one can't be reached: the same keys twice, or keys that start another binding's
+    /// sequence (`g` would fire before `g g` could be completed). Global bindings are checked against every pane
+    fn conflicts(&self) -> Vec<String> {
+        let mut conflicts: Vec<String> = Vec::new();
+        for (i, a) in self.bindings.iter().enumerate() {
+            for b in &self.bindings[i + 1..] {
+                let overlapping = a.context == b.context || a.context == Context::Global || b.context == Context::Global;
+                if !overlapping || !(a.keys.starts_with(&b.keys) || b.keys.starts_with(&a.keys)) { continue; }
+                // the same keys for the same action twice is harmless
+                if a.keys == b.keys && a.action == b.action && a.context == b.context { continue; }
+                conflicts.push(format!(
+                    "\"{}\" ({} in [keys.{}]) and \"{}\" ({} in [keys.{}]) get in each other's way",
+                    a.keys_label(), a.action.name(), a.context.name(),
+                    b.keys_label(), b.action.name(), b.context.name(),
+                ));
+            }
+        }
+        conflicts
+    }
+
+    /// The keys bound in `context` (not including the global ones), with what they do, in the order of the
+    /// defaults. An action with several bindings is one entry with its keys separated by commas
+    pub fn entries(&self, context: Context) -> Vec<(String, Action)> {
+        let mut entries: Vec<(String, Action)> = Vec::new();
+        for binding in self.bindings.iter().filter(|b| b.context == context) {
+            match entries.last_mut() {
+                Some((keys, action)) if *action == binding.action => {
+                    keys.push_str(", ");
+                    keys.push_str(&binding.keys_label());
+                }
+                _ => entries.push((binding.keys_label(), binding.action)),
+            }
+        }
+        entries
+    }
+
+    /// The first key bound to `action` in `context` or globally, written like "[q]" for block titles, or nothing if
+    /// it isn't bound
+    pub fn hint(&self, action: Action, context: Context) -> String {
+        self.bindings.iter()
+            .find(|b| b.action == action && (b.context == context || b.context == Context::Global))
+            .map(|b| format!("[{}]", b.keys_label()))
+            .unwrap_or_default()
+    }
+
+    /// Takes a key press while `context` has focus. `pending` holds the keys of a sequence typed so far; the
+    /// action is returned once a sequence is complete, a key that continues no sequence starts over on its own
+    pub fn resolve(&self, context: Context, pending: &mut Vec<KeyChord>, key: KeyChord) -> Option<Action> {
+        pending.push(key);
+        loop {
+            let mut continues = false;
+            for binding in self.bindings.iter().filter(|b| b.context == Context::Global || b.context == context) {
+                if !binding.keys.starts_with(pending) { continue; }
+                if binding.keys.len() == pending.len() {
+                    pending.clear();
+                    return Some(binding.action);
+                }
+                continues = true;
+            }
+            if continues { return None; }
+            if pending.len() <= 1 {
+                pending.clear();
+                return None;
+            }
+            pending.drain(..pending.len() - 1);
+        }
+    }
+}
+
+fn single_char(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(code: KeyCode, modifiers: KeyModifiers) -> KeyChord {
+        KeyChord { code, modifiers }
+    }
+
+    fn press(c: char) -> KeyChord {
+        KeyChord::from(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE))
+    }
+
+    fn keymap(config: &str) -> Result<Keymap> {
+        Keymap::new(&toml::from_str(config).unwrap())
+    }
+
+    fn errors(config: &str) -> String {
+        keymap(config).err().map(|e| e.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn parsing_chords() {
+        assert_eq!(KeyChord::parse("alt+q"), Ok(chord(KeyCode::Char('q'), KeyModifiers::ALT)));
+        assert_eq!(KeyChord::parse("ALT+Enter"), Ok(chord(KeyCode::Enter, KeyModifiers::ALT)));
+        assert_eq!(KeyChord::parse("ctrl++"), Ok(chord(KeyCode::Char('+'), KeyModifiers::CONTROL)));
+        assert_eq!(KeyChord::parse("+"), Ok(chord(KeyCode::Char('+'), KeyModifiers::NONE)));
+        assert_eq!(KeyChord::parse("shift+left"), Ok(chord(KeyCode::Left, KeyModifiers::SHIFT)));
+        assert_eq!(KeyChord::parse("space"), Ok(chord(KeyCode::Char(' '), KeyModifiers::NONE)));
+        assert_eq!(KeyChord::parse("f5"), Ok(chord(KeyCode::F(5), KeyModifiers::NONE)));
+        assert!(KeyChord::parse("f13").is_err());
+        assert!(KeyChord::parse("hyper+x").is_err());
+        assert!(KeyChord::parse("ctrl+").is_err());
+        assert!(KeyChord::parse("qq").is_err());
+    }
+
+    #[test]
+    fn shift_folds_into_letters() {
+        let upper = chord(KeyCode::Char('N'), KeyModifiers::NONE);
+        assert_eq!(KeyChord::parse("shift+n"), Ok(upper));
+        assert_eq!(KeyChord::parse("N"), Ok(upper));
+        // terminals report it either way
+        assert_eq!(KeyChord::from(KeyEvent::new(KeyCode::Char('N'), KeyModifiers::SHIFT)), upper);
+        assert_eq!(KeyChord::from(KeyEvent::new(KeyCode::Char('n'), KeyModifiers::SHIFT)), upper);
+        assert_eq!(KeyChord::from(KeyEvent::new(KeyCode::Char('!'), KeyModifiers::SHIFT)), press('!'));
+
+        let back_tab = chord(KeyCode::BackTab, KeyModifiers::NONE);
+        assert_eq!(KeyChord::parse("shift+tab"), Ok(back_tab));
+        assert_eq!(KeyChord::from(KeyEvent::new(KeyCode::BackTab, KeyModifiers::SHIFT)), back_tab);
+        assert_eq!(back_tab.to_string(), "shift+tab");
+    }
+
+    #[test]
+    fn shift_only_with_letters() {
+        assert!(KeyChord::parse("shift+1").is_err());
+        assert!(KeyChord::parse("shift+space").is_err());
+        assert!(errors("[player]\nseek_10 = \"shift+1\"").contains("shift only goes with letters"));
+        assert!(keymap("[player]\nseek_10 = \"!\"").is_ok());
+    }
+
+    #[test]
+    fn chords_written_back() {
+        for text in ["alt+q", "ctrl++", "N", "space", "shift+left", "pagedown", "f12", "enter"] {
+            assert_eq!(KeyChord::parse(text).map(|c| c.to_string()).as_deref(), Ok(text));
+        }
+    }
+
+    #[test]
+    fn defaults_load() {
+        let keymap = Keymap::new(&KeysConfig::default()).unwrap();
+        assert_eq!(keymap.hint(Action::Quit, Context::Tree), "[alt+q]");
+        assert_eq!(keymap.hint(Action::First, Context::Queue), "[g g]");
+    }
+
+    #[test]
+    fn sequences() {
+        let keymap = Keymap::new(&KeysConfig::default()).unwrap();
+        let mut pending = Vec::new();
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('g')), None);
+        assert_eq!(pending, [press('g')]);
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('g')), Some(Action::First));
+        assert!(pending.is_empty());
+
+        // a key that doesn't go on from "g" counts on its own
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('g')), None);
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('x')), Some(Action::Cut));
+        assert!(pending.is_empty());
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('g')), None);
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('z')), None);
+        assert!(pending.is_empty());
+
+        // "g" alone is the seek prompt in the player
+        assert_eq!(keymap.resolve(Context::Player, &mut pending, press('g')), Some(Action::SeekPrompt));
+    }
+
+    #[test]
+    fn overrides() {
+        let keymap = keymap("[queue]\nremove = [\"d d\", \"backspace\"]\nundo = []\n").unwrap();
+        let mut pending = Vec::new();
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('d')), None);
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('d')), Some(Action::Remove));
+        assert_eq!(keymap.resolve(Context::Queue, &mut pending, press('u')), None);
+        assert_eq!(keymap.hint(Action::Undo, Context::Queue), "");
+
+        assert!(errors("[queue]\nfly = \"f\"").contains("no fly action"));
+        assert!(errors("[tree]\nseek_prompt = \"g\"").contains("no seek_prompt action"));
+        assert!(errors("[queue]\nremove = \"\"").contains("no key given"));
+    }
+
+    #[test]
+    fn sequence_conflicts() {
+        let errors = errors("[queue]\nundo = \"g\"");
+        assert!(errors.contains("\"g g\" (first in [keys.queue]) and \"g\" (undo in [keys.queue])"), "{}", errors);
+        // the same prefix in different panes is fine
+        assert!(keymap("[tree]\nenqueue = \"g\"\nfirst = \"home\"").is_ok());
+    }
+
+    #[test]
+    fn global_conflicts() {
+        let errors = errors("[global]\nquit = \"d\"");
+        assert!(errors.contains("\"d\" (quit in [keys.global]) and \"d\" (remove in [keys.queue])"), "{}", errors);
+        assert!(keymap("[global]\nquit = \"g\"").is_err());
+    }
+}
@@ -1,12 +1,62 @@
-use std::collections::HashMap;
+use std::{cmp::Ordering, collections::{hash_map::Entry, HashMap, HashSet}, fs, io, path::{Path, PathBuf}, sync::mpsc::Sender, thread};
+use serde::{Deserialize, Serialize};
 use tui_tree_widget::TreeItem;
-use crate::playlist::{dir_to_songs, Song};
+use crate::{
+	config::{cache_dir, LibraryConfig},
+	events::{AppEvent, TaskMessage},
+	grouping::{primary_artist, Group, GroupKind, View},
+	index::LibraryIndex,
+	playlist::{dir_to_songs, SkippedFile, Song, SongError},
+	playlist_file::{is_playlist, read_entries, resolve_entry, write_m3u8, SavedPlaylist},
+};
+
+/// tree identifier of the playlists branch, with a character no tag contains so it can't clash with an artist
+pub const PLAYLISTS_ID: &str = "\u{0}playlists";
+
+/// How the library tree orders its groups and tracks. Names are compared ignoring case and a leading article,
+/// groups for songs missing the tag (the unknown artist, ...) always go last
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum SortOrder {
+	/// groups by name, albums by year, tracks by disc and track number
+	#[default]
+	Artist,
+	/// groups, albums and tracks all by name
+	Title,
+	/// groups with the newest additions to the library first
+	RecentlyAdded,
+}
+
+impl SortOrder {
+	pub fn cycle(self) -> Self {
+		match self {
+			SortOrder::Artist => SortOrder::Title,
+			SortOrder::Title => SortOrder::RecentlyAdded,
+			SortOrder::RecentlyAdded => SortOrder::Artist,
+		}
+	}
+
+	pub fn label(self) -> &'static str {
+		match self {
+			SortOrder::Artist => "by artist",
+			SortOrder::Title => "by title",
+			SortOrder::RecentlyAdded => "recently added",
+		}
+	}
+}
 
 pub struct Library {
 	// pub state: TreeState<&'a str>,
 	// pub state: &'a mut TreeState<&'a String>,
 	pub tree_entries: Vec<TreeItem<'static, String>>,
-	pub songs: Vec<Song>
+	pub songs: Vec<Song>,
+	/// files the last scan couldn't read, kept around so the UI can tell the user about them
+	pub skipped: Vec<SkippedFile>,
+	/// the playlists in the playlist directory, by name
+	pub playlists: Vec<SavedPlaylist>,
+	pub sort: SortOrder,
+	pub view: View,
+	pub config: LibraryConfig,
+	pub index: LibraryIndex
 }
 
 impl Clone for Library {
@@ -14,48 +64,442 @@ impl Clone for Library {
         Library {
 			songs: self.songs.clone(),
 			tree_entries: self.tree_entries.clone(),
+			skipped: self.skipped.clone(),
+			playlists: self.playlists.clone(),
+			sort: self.sort,
+			view: self.view,
+			config: self.config.clone(),
+			index: self.index.clone(),
 		}
     }
 }
 
 impl Library {
 	// pub fn new(state: &'a mut TreeState<&'a String>) -> Self {
-	pub fn new() -> Self {
+	pub fn new(config: LibraryConfig) -> Self {
 		Self {
             songs: Vec::new(),
 			tree_entries: Vec::new(),
+			skipped: Vec::new(),
+			playlists: Vec::new(),
+			sort: SortOrder::default(),
+			view: View::default(),
+			config,
+			index: cache_dir()
+				.map(|d| LibraryIndex::load(d.join("index.json")))
+				.unwrap_or_else(LibraryIndex::in_memory),
         }
 	}
 	
-	pub fn update_tree_entries(&mut self) {
-		let mut root: HashMap<String, HashMap<String, Vec<Song>>> = HashMap::new();
-		let song_list = &dir_to_songs(&"./music/".to_owned());
-		// song_list.into_iter().filter(|w| matches(*w.artist, w1)).collect::<Vec<Word>>()
-		for song in song_list {
-			let mut root_clone = root.clone();
-			if !root_clone.contains_key(&song.artist.clone()) {root_clone.insert(song.artist.clone(), HashMap::new());}
-			let artist_content: &mut HashMap<String, Vec<Song>> = root_clone.get_mut(&(song.artist.clone())).unwrap();
-			if !artist_content.contains_key(&song.album.clone()) {artist_content.insert(song.album.clone(), Vec::new());}
-			let mut album_content: Vec<Song> = artist_content.get(&(song.album.clone())).unwrap().clone();
-
-			album_content.push(song.clone());
-			artist_content.insert(song.album.clone(), album_content);
-			root.insert(song.artist.clone(), artist_content.clone());
-		}
-		
-		let mut items: Vec<TreeItem<'_, String>> = Vec::new(); // = TreeItem::new_leaf("l", "leaf");
+	/// Reads all configured roots into `songs`. A file reachable from more than one root (overlapping roots or
+	/// symlinks) is only taken once. Only files the index doesn't know yet (or that changed) get probed, and the
+	/// refreshed index is written back afterwards. The saved playlists are read once the songs are known
+	pub fn scan(&mut self, progress: &mut dyn FnMut(usize)) {
+		// the patterns were already checked when the config was loaded
+		let include = self.config.include_patterns().unwrap_or_default();
+		let exclude = self.config.exclude_patterns().unwrap_or_default();
+		// canonical path -> index into `songs`
+		let mut seen: HashMap<PathBuf, usize> = HashMap::new();
+		let mut read = 0;
+		let mut on_file = || {
+			read += 1;
+			progress(read);
+		};
+		self.songs.clear();
+		self.skipped.clear();
+		for root in &self.config.roots {
+			let (songs, skipped) = dir_to_songs(root, &include, &exclude, &mut self.index, &mut on_file);
+			self.skipped.extend(skipped);
+			for song in songs {
+				let canonical = fs::canonicalize(&song.path).unwrap_or_else(|_| PathBuf::from(&song.path));
+				if let Entry::Vacant(entry) = seen.entry(canonical) {
+					entry.insert(self.songs.len());
+					self.songs.push(song);
+				}
+			}
+		}
+		self.index.retain(&self.songs.iter().map(|s| s.path.clone()).collect());
+		// a cache that can't be written only costs time on the next launch
+		let _ = self.index.save();
+		self.find_compilations();
+		self.load_playlists(&seen);
+	}
 
-		for artist in root.keys() {
-			let mut artist_items: Vec<TreeItem<'_, String>> = Vec::new(); // = TreeItem::new_leaf("l", "leaf");
-			for album in root.get(artist).expect("no such key in artist hashmap").keys() {
-				let mut album_items: Vec<TreeItem<'_, String>> = Vec::new(); // = TreeItem::new_leaf("l", "leaf");
-				for song in root.get(artist).unwrap().get(album).unwrap() {
-					album_items.push(TreeItem::new_leaf(song.path.to_string(), song.title.to_string()));
+	/// Flags albums that neither name an album artist nor say they're a compilation but have tracks by different
+	/// artists as compilations, so they stay together under "Various Artists". Tracks count as one album when
+	/// they share the album name and directory
+	fn find_compilations(&mut self) {
+		let mut artists: HashMap<(&Path, &str), HashSet<&str>> = HashMap::new();
+		for song in self.songs.iter().filter(|s| s.album_artist.is_none() && !s.compilation) {
+			let (Some(album), Some(artist)) = (&song.album, &song.artist) else { continue };
+			let dir = Path::new(&song.path).parent().unwrap_or(Path::new(""));
+			artists.entry((dir, album)).or_default().insert(primary_artist(artist));
+		}
+		let mixed: HashSet<(PathBuf, String)> = artists.into_iter()
+			.filter(|(_, artists)| artists.len() > 1)
+			.map(|((dir, album), _)| (dir.to_path_buf(), album.to_owned()))
+			.collect();
+		if mixed.is_empty() { return; }
+		for song in self.songs.iter_mut().filter(|s| s.album_artist.is_none()) {
+			let dir = Path::new(&song.path).parent().unwrap_or(Path::new("")).to_path_buf();
+			if let Some(album) = &song.album {
+				if mixed.contains(&(dir, album.clone())) { song.compilation = true; }
+			}
+		}
+	}
+
+	/// Reads every playlist file in the playlist directory. Entries are matched up with the library by their
+	/// canonical path in `known`, files outside of the library are read on their own
+	fn load_playlists(&mut self, known: &HashMap<PathBuf, usize>) {
+		self.playlists.clear();
+		let Some(dir) = self.config.playlists.clone() else { return };
+		let files = match fs::read_dir(&dir) {
+			Ok(files) => files,
+			// no playlist saved yet
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+			Err(err) => {
+				self.skipped.push(SkippedFile { path: dir.display().to_string(), error: SongError::Io(err) });
+				return;
+			}
+		};
+		let mut paths: Vec<PathBuf> = files.filter_map(|f| f.ok()).map(|f| f.path()).filter(|p| is_playlist(p)).collect();
+		paths.sort();
+		for path in paths {
+			let entries = match read_entries(&path) {
+				Ok(entries) => entries,
+				Err(err) => {
+					self.skipped.push(SkippedFile { path: path.display().to_string(), error: SongError::Io(err) });
+					continue;
+				}
+			};
+			let mut playlist = SavedPlaylist { name: file_stem(&path), path: path.clone(), songs: Vec::new(), missing: Vec::new() };
+			for entry in entries {
+				let song = resolve_entry(&entry, &dir, &self.config.roots).and_then(|file| {
+					match fs::canonicalize(&file).ok().and_then(|c| known.get(&c)) {
+						Some(index) => Some(self.songs[*index].clone()),
+						None => Song::new(file.display().to_string()).ok(),
+					}
+				});
+				match song {
+					Some(song) => playlist.songs.push(song),
+					None => playlist.missing.push(entry),
 				}
-				artist_items.push(TreeItem::new(album.to_string(), album.to_string(), album_items).unwrap());
 			}
-			items.push(TreeItem::new(artist.to_string(), artist.to_string(), artist_items).unwrap());
+			self.playlists.push(playlist);
 		}
+	}
+
+	/// Saves `songs` as `<name>.m3u8` in the playlist directory, overwriting a playlist of the same name, and
+	/// shows it in the tree
+	pub fn save_playlist(&mut self, name: &str, songs: Vec<Song>) -> io::Result<PathBuf> {
+		let Some(dir) = &self.config.playlists else {
+			return Err(io::Error::new(io::ErrorKind::NotFound, "no playlist directory configured"));
+		};
+		let path = dir.join(format!("{}.m3u8", name));
+		write_m3u8(&path, &songs)?;
+		self.playlists.retain(|p| p.path != path);
+		self.playlists.push(SavedPlaylist { name: name.to_owned(), path: path.clone(), songs, missing: Vec::new() });
+		self.playlists.sort_by(|a, b| a.path.cmp(&b.path));
+		self.update_tree_entries();
+		Ok(path)
+	}
+
+	/// Scans the library described by `config` on a separate thread, reporting progress and the finished library
+	/// through `events`
+	pub fn scan_in_background(config: LibraryConfig, events: Sender<AppEvent>) {
+		thread::spawn(move || {
+			let mut library = Library::new(config);
+			library.scan(&mut |read| { let _ = events.send(AppEvent::Task(TaskMessage::ScanProgress(read))); });
+			library.update_tree_entries();
+			let _ = events.send(AppEvent::Task(TaskMessage::ScanFinished(Box::new(library))));
+		});
+	}
+
+	/// Rebuilds the tree from `songs`, grouped by the current view and in the order `sort` puts them
+	pub fn update_tree_entries(&mut self) {
+		let mut items: Vec<TreeItem<'_, String>> = Vec::new(); // = TreeItem::new_leaf("l", "leaf");
+		if !self.playlists.is_empty() { items.push(self.playlists_tree()); }
+		items.extend(tree_level(&self.arranged(self.songs.iter()), 0));
 		self.tree_entries = items;
 	}
+
+	/// Switches to the next sort order and rebuilds the tree in it
+	pub fn cycle_sort(&mut self) {
+		self.sort = self.sort.cycle();
+		self.update_tree_entries();
+	}
+
+	/// Switches to the next view and rebuilds the tree for it
+	pub fn cycle_view(&mut self) {
+		self.view = self.view.cycle();
+		self.update_tree_entries();
+	}
+
+	/// `songs` with the groups the current view puts them under, in the order of the tree. Songs sharing a group
+	/// come out next to each other
+	fn arranged<'s>(&self, songs: impl Iterator<Item = &'s Song>) -> Vec<Row<'s>> {
+		let grouping = self.view.grouping();
+		let grouped: Vec<(Vec<Group>, &Song)> = songs.map(|s| (grouping.groups(s, &self.config.roots), s)).collect();
+
+		// groups are ordered by what is known about all of their songs, so songs of an album with a year
+		// missing on one track still stay together
+		let mut stats: HashMap<Vec<String>, (Option<i32>, u64)> = HashMap::new();
+		for (groups, song) in &grouped {
+			let added = self.index.added(&song.path).unwrap_or(0);
+			for depth in 1..=groups.len() {
+				let key = groups[..depth].iter().map(Group::id).collect();
+				let (year, newest) = stats.entry(key).or_insert((song.year, added));
+				*year = match (*year, song.year) {
+					(Some(a), Some(b)) => Some(a.min(b)),
+					(a, b) => a.or(b),
+				};
+				*newest = (*newest).max(added);
+			}
+		}
+		let mut rows: Vec<Row> = grouped.iter().map(|(groups, song)| {
+			let levels = (1..=groups.len()).map(|depth| {
+				let key: Vec<String> = groups[..depth].iter().map(Group::id).collect();
+				let (year, newest) = stats[&key];
+				Level { group: groups[depth - 1].clone(), year, newest }
+			}).collect();
+			(levels, *song)
+		}).collect();
+
+		let by_file_name = grouping.by_file_name();
+		rows.sort_by(|(a_levels, a), (b_levels, b)| {
+			for depth in 0..a_levels.len().max(b_levels.len()) {
+				let order = match (a_levels.get(depth), b_levels.get(depth)) {
+					(Some(a), Some(b)) => compare_levels(a, b, self.sort),
+					// a group's subgroups go before the songs right in it
+					(Some(_), None) => Ordering::Less,
+					(None, Some(_)) => Ordering::Greater,
+					(None, None) => Ordering::Equal,
+				};
+				if order != Ordering::Equal { return order; }
+			}
+			let tracks = match self.sort {
+				SortOrder::Title => Ordering::Equal,
+				_ if by_file_name => compare_names(file_name(a), file_name(b)),
+				// tracks without a number go to the end of their disc
+				_ => (a.disc_num.is_none(), a.disc_num, a.track_num.is_none(), a.track_num)
+					.cmp(&(b.disc_num.is_none(), b.disc_num, b.track_num.is_none(), b.track_num)),
+			};
+			tracks.then_with(|| compare_names(&a.title, &b.title)).then_with(|| a.path.cmp(&b.path))
+		});
+		rows
+	}
+
+	fn playlists_tree(&self) -> TreeItem<'static, String> {
+		let playlists = self.playlists.iter().map(|playlist| {
+			// entries are told apart by position, the same song can be in a playlist more than once
+			let entries = playlist.songs.iter().enumerate()
+				.map(|(i, song)| TreeItem::new_leaf(i.to_string(), song.title.to_string()))
+				.collect();
+			let text = match playlist.missing.len() {
+				0 => playlist.name.clone(),
+				missing => format!("{} ({} missing)", playlist.name, missing),
+			};
+			TreeItem::new(playlist.path.display().to_string(), text, entries).unwrap()
+		}).collect();
+		TreeItem::new(PLAYLISTS_ID.to_owned(), "Playlists".to_owned(), playlists).unwrap()
+	}
+
+	/// The songs below the tree node at `selection` (the identifiers from the root down, as `TreeState::selected`
+	/// gives them) in the order the tree shows them
+	pub fn songs_under(&self, selection: &[String]) -> Vec<Song> {
+		if selection.first().is_some_and(|id| id == PLAYLISTS_ID) { return self.playlist_songs(&selection[1..]); }
+		self.arranged(self.songs.iter())
+			.into_iter()
+			.filter(|(levels, song)| selection.iter().enumerate().all(|(depth, id)| match levels.get(depth) {
+				Some(level) => level.group.id() == *id,
+				// the song itself
+				None => depth == levels.len() && song.path == *id,
+			}))
+			.map(|(_, song)| song.clone())
+			.collect()
+	}
+
+	/// Identifiers of the tree node showing `song`, from the root down, for `TreeState::select`
+	pub fn tree_path(&self, song: &Song) -> Vec<String> {
+		let mut path: Vec<String> = self.view.grouping().groups(song, &self.config.roots).iter().map(Group::id).collect();
+		path.push(song.path.clone());
+		path
+	}
+
+	/// Like [`Library::songs_under`] within the playlists branch, keeping the playlists' own order
+	fn playlist_songs(&self, selection: &[String]) -> Vec<Song> {
+		let find = |path: &String| self.playlists.iter().find(|p| p.path.display().to_string() == *path);
+		match selection {
+			[path, entry] => find(path)
+				.and_then(|p| entry.parse::<usize>().ok().and_then(|i| p.songs.get(i)))
+				.into_iter()
+				.cloned()
+				.collect(),
+			[path] => find(path).map(|p| p.songs.clone()).unwrap_or_default(),
+			_ => Vec::new(),
+		}
+	}
+}
+
+fn file_stem(path: &Path) -> String {
+	path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// A group with what its songs say about how to order it
+struct Level {
+	group: Group,
+	/// the earliest year among the group's songs
+	year: Option<i32>,
+	/// when the newest of the group's songs was added to the library
+	newest: u64,
+}
+
+/// A song with the groups it sits in, from the root down
+type Row<'s> = (Vec<Level>, &'s Song);
+
+/// The tree items for `rows` below `depth` groups; `rows` have to be ordered by [`Library::arranged`]
+fn tree_level(rows: &[Row], depth: usize) -> Vec<TreeItem<'static, String>> {
+	let mut items: Vec<TreeItem<'static, String>> = Vec::new();
+	let id = |row: &Row| row.0.get(depth).map(|l| l.group.id());
+	for run in rows.chunk_by(|a, b| id(a) == id(b)) {
+		match run[0].0.get(depth) {
+			Some(level) => {
+				items.push(TreeItem::new(level.group.id(), level.group.label.clone(), tree_level(run, depth + 1)).unwrap());
+			}
+			None => items.extend(run.iter().map(|(_, song)| TreeItem::new_leaf(song.path.clone(), song.title.clone()))),
+		}
+	}
+	items
+}
+
+/// Orders two groups on the same level, groups standing for missing tags last
+fn compare_levels(a: &Level, b: &Level, sort: SortOrder) -> Ordering {
+	let recency = match sort {
+		SortOrder::RecentlyAdded => b.newest.cmp(&a.newest),
+		_ => Ordering::Equal,
+	};
+	let by_kind = match (a.group.kind, b.group.kind) {
+		(GroupKind::Number(x), GroupKind::Number(y)) => x.cmp(&y),
+		// albums without a year go last
+		(GroupKind::Album, GroupKind::Album) if sort == SortOrder::Artist => (a.year.is_none(), a.year)
+			.cmp(&(b.year.is_none(), b.year))
+			.then_with(|| compare_names(&a.group.label, &b.group.label)),
+		(GroupKind::Folder, GroupKind::Folder) => a.group.label.to_lowercase().cmp(&b.group.label.to_lowercase()),
+		_ => compare_names(&a.group.label, &b.group.label),
+	};
+	recency
+		.then(a.group.unknown.cmp(&b.group.unknown))
+		.then(by_kind)
+		.then_with(|| a.group.label.cmp(&b.group.label))
+}
+
+/// Orders names case-insensitively and without a leading "The", "A" or "An". Names equal that way are ordered as
+/// written so the order never depends on where they came from
+fn compare_names(a: &str, b: &str) -> Ordering {
+	sort_key(a).cmp(&sort_key(b)).then_with(|| a.cmp(b))
+}
+
+fn sort_key(name: &str) -> String {
+	let lower = name.trim().to_lowercase();
+	["the ", "a ", "an "].iter()
+		.find_map(|article| lower.strip_prefix(article))
+		.map(|rest| rest.trim_start().to_owned())
+		.unwrap_or(lower)
+}
+
+fn file_name(song: &Song) -> &str {
+	Path::new(&song.path).file_name().and_then(|n| n.to_str()).unwrap_or(&song.path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::grouping::VARIOUS_ARTISTS;
+
+	/// A library of `songs` that never touches the disk
+	fn library(songs: Vec<Song>) -> Library {
+		Library {
+			tree_entries: Vec::new(),
+			songs,
+			skipped: Vec::new(),
+			playlists: Vec::new(),
+			sort: SortOrder::default(),
+			view: View::default(),
+			config: LibraryConfig::default(),
+			index: LibraryIndex::in_memory(),
+		}
+	}
+
+	fn song(path: &str, artist: &str, album: &str) -> Song {
+		let mut song = Song::untagged(path);
+		song.artist = Some(artist.to_owned());
+		song.album = Some(album.to_owned());
+		song
+	}
+
+	#[test]
+	fn names_without_articles() {
+		assert_eq!(compare_names("The Beatles", "Beach Boys"), Ordering::Greater);
+		assert_eq!(compare_names("A Tribe Called Quest", "Tupac"), Ordering::Less);
+		assert_eq!(compare_names("An Albatross", "Air"), Ordering::Greater);
+		assert_eq!(compare_names("abba", "ACDC"), Ordering::Less);
+		// only whole words are articles
+		assert_eq!(compare_names("Theatre", "Ticket"), Ordering::Less);
+		assert_eq!(compare_names("Anathema", "Beck"), Ordering::Less);
+		assert_eq!(compare_names("Aerosmith", "Beck"), Ordering::Less);
+	}
+
+	#[test]
+	fn equal_names_ordered_as_written() {
+		assert_eq!(sort_key("  The   Who "), "who");
+		assert_eq!(compare_names("The Who", "Who"), Ordering::Less);
+		assert_eq!(compare_names("who", "Who"), Ordering::Greater);
+		assert_eq!(compare_names("Who", "Who"), Ordering::Equal);
+	}
+
+	#[test]
+	fn unknown_groups_apart_from_same_named_ones() {
+		let mut tagged = Song::untagged("/music/tagged.mp3");
+		tagged.artist = Some("Unknown Artist".to_owned());
+		tagged.album = Some("Unknown Album".to_owned());
+		let untagged = Song::untagged("/music/untagged.mp3");
+		let mut library = library(vec![tagged.clone(), untagged.clone()]);
+		for view in [View::AlbumArtist, View::Artist, View::Genre] {
+			library.view = view;
+			library.update_tree_entries();
+			// two nodes labelled "Unknown Artist" side by side, which the tree only takes with different identifiers
+			assert!(tui_tree_widget::Tree::new(&library.tree_entries).is_ok());
+			for song in [&tagged, &untagged] {
+				let path = library.tree_path(song);
+				let artist = &path[..path.len() - 2];
+				assert_eq!(library.songs_under(artist).iter().map(|s| &s.path).collect::<Vec<&String>>(), [&song.path]);
+			}
+		}
+	}
+
+	#[test]
+	fn compilations_found_by_their_artists() {
+		let mut library = library(vec![
+			song("/music/mix/1.mp3", "Ólafur Arnalds", "Mix"),
+			song("/music/mix/2.mp3", "Nils Frahm", "Mix"),
+			// guests don't make an album a compilation
+			song("/music/solo/1.mp3", "İzel İnce", "Solo"),
+			song("/music/solo/2.mp3", "İzel İnce feat. Someone", "Solo"),
+			// nor does the same album name in another directory
+			song("/music/other/1.mp3", "Someone Else", "Solo"),
+			// an album artist says who the album is by
+			song("/music/split/1.mp3", "A", "Split"),
+			song("/music/split/2.mp3", "B", "Split"),
+		]);
+		library.songs[5].album_artist = Some("A & B".to_owned());
+		library.songs[6].album_artist = Some("A & B".to_owned());
+		library.find_compilations();
+		let flagged: Vec<bool> = library.songs.iter().map(|s| s.compilation).collect();
+		assert_eq!(flagged, [true, true, false, false, false, false, false]);
+
+		library.update_tree_entries();
+		let path = library.tree_path(&library.songs[1]);
+		assert_eq!(path[..2], [VARIOUS_ARTISTS, "Mix"]);
+		assert_eq!(library.songs_under(&path[..1]).len(), 2);
+	}
 }
@@ -1,24 +1,65 @@
-use std::{fs::File, io::BufReader};
+use std::{io::stdout, panic, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
 use color_eyre::Result;
-use crossterm::event::KeyModifiers;
+use crossterm::{
+    event::{DisableMouseCapture, EnableMouseCapture, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
+    execute,
+};
 use ratatui::{
     buffer::Buffer,
-    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
-    layout::{Constraint, Layout, Rect},
+    crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout, Margin, Position, Rect},
     style::{palette::tailwind::SLATE, Modifier, Style, Stylize},
     // symbols,
-    // text::{Line, Text},
-    widgets::{Block, BorderType, Borders, HighlightSpacing, List, StatefulWidget, Widget},
+    text::{Line, Span, Text},
+    widgets::{
+        Block, BorderType, Borders, Clear, HighlightSpacing, LineGauge, List, ListItem, Paragraph, StatefulWidget,
+        Widget, Wrap,
+    },
     DefaultTerminal,
 };
-use rodio::{Decoder, OutputStream, Sink};
+mod art;
+use art::AlbumArt;
+mod config;
+use config::Config;
+mod events;
+mod formats;
+use events::{AppEvent, Events, TaskMessage};
+mod grouping;
+use grouping::album_artist_of;
+mod img_to_ascii;
+mod index;
+mod keymap;
+use keymap::{Action, Context, KeyChord, Keymap};
 mod library;
+#[cfg(feature = "opus")]
+mod opus;
 use library::Library;
+mod player;
+use player::{parse_seek_target, Player, PlayerEvent};
 mod playlist;
-use playlist::{Queue, Song};
+use playlist::{Queue, RepeatMode, Song};
+mod playlist_file;
+mod search;
+mod source;
+use search::Search;
+mod state;
+use state::SessionState;
 use tui_tree_widget::{Tree, TreeState};
 
+/// How often the screen is refreshed while something is playing
+const TICK_RATE: Duration = Duration::from_millis(250);
+/// How far one press of the volume keys moves the volume slider, and with [ALT] held
+const VOLUME_STEP: f32 = 0.05;
+const VOLUME_STEP_FINE: f32 = 0.01;
+/// How often the session is saved while running, on top of saving it on exit
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest time between two clicks on the same queue entry that still counts as a double click
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+/// Lines a queue entry takes up: title and artist
+const QUEUE_ITEM_HEIGHT: u16 = 2;
+/// Lines or entries one notch of the scroll wheel moves
+const SCROLL_STEP: u16 = 3;
 const SELECTED_STYLE: Style = Style::new().bg(SLATE.c800).add_modifier(Modifier::BOLD);
 const FOCUS_ID_QUEUE: i8 = 0;
 const FOCUS_ID_PLAYER: i8 = 1;
@@ -28,89 +69,523 @@ const FOCUS_ID_TREE: i8 = 2;
 
 fn main() -> Result<()> {
     color_eyre::install()?;
+    let config = Config::load()?;
+    let keymap = Keymap::new(&config.keys)?;
+    let events = Events::new(TICK_RATE);
+    let player = Player::new(events.sender())?;
+    let mut tree_state = TreeState::default();
+    let mut app = App::default(player, &mut tree_state, config, keymap);
+    app.restore_session(SessionState::load());
     let terminal = ratatui::init();
-    let app_result =
-        App::default(
-            &mut Sink::try_new(&OutputStream::try_default().unwrap().1).unwrap(),
-            &mut TreeState::default()
-        ).run(terminal);
+    // ratatui's panic hook gives back the screen and the keyboard, the mouse is ours to give back
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = execute!(stdout(), DisableMouseCapture);
+        hook(info);
+    }));
+    let app_result = execute!(stdout(), EnableMouseCapture)
+        .map_err(Into::into)
+        .and_then(|_| app.run(terminal, events));
+    let _ = execute!(stdout(), DisableMouseCapture);
     ratatui::restore();
     app_result
 }
 
+/// Where songs picked in the library tree end up in the queue
+enum Enqueue {
+    /// after everything already queued
+    Append,
+    /// right after the current track
+    Next,
+    /// in place of the whole queue, starting playback from the first of them
+    Replace,
+}
+
 struct App<'a> {
-    pub audio_controls: &'a Sink,
+    pub player: Player,
     pub focus: i8,
     pub queue: Queue,
     pub should_exit: bool,
     pub library: Library,
-    pub tree_state: &'a mut TreeState<String>
+    pub tree_state: &'a mut TreeState<String>,
+    /// whether the "files skipped" panel from the last library scan is still on screen
+    pub show_skipped: bool,
+    /// whether the panel with everything known about the selected queue entry is open
+    pub show_details: bool,
+    /// whether the list of key bindings is open, and how far it is scrolled down
+    pub show_help: bool,
+    pub help_scroll: u16,
+    pub album_art: AlbumArt,
+    /// files read so far by the library scan running in the background, `None` when no scan is running
+    pub scan_progress: Option<usize>,
+    /// what has been typed into the "seek to" prompt while it is open
+    pub seek_prompt: Option<String>,
+    /// the name typed into the "save queue as playlist" prompt while it is open
+    pub save_prompt: Option<String>,
+    /// outcome of the last playlist save, shown under the queue until the next key press
+    pub queue_message: Option<String>,
+    /// when the session was last written to the state file
+    pub last_saved: Instant,
+    /// the `/` search popup while it is open
+    pub search: Option<Search>,
+    pub keymap: Keymap,
+    /// keys of a binding like `g g` typed so far
+    pub pending_keys: Vec<KeyChord>,
+    /// where the progress bar was drawn last, for mouse seeking
+    pub progress_area: Rect,
+    /// where the panes were drawn last, for telling which one the mouse is over
+    pub queue_area: Rect,
+    pub player_area: Rect,
+    pub tree_area: Rect,
+    /// when and on which queue entry the last click landed, to spot double clicks
+    pub last_click: Option<(Instant, usize)>,
+    /// the position (0 to 1) the progress bar is being dragged to, the seek happens when the button is released
+    pub seek_drag: Option<f64>
 }
 
 impl<'a> App<'a> {
-    fn default(controller: &'a mut Sink, tree_state: &'a mut TreeState<String>) -> Self {
+    fn default(player: Player, tree_state: &'a mut TreeState<String>, config: Config, keymap: Keymap) -> Self {
         Self {
             should_exit: false,
             focus: 0,
-            queue: Queue::new::<Song>(),
-            library: Library::new(),
-            tree_state: tree_state,
-            audio_controls: controller,
+            queue: Queue::new(),
+            library: Library::new(config.library),
+            tree_state,
+            player,
+            show_skipped: false,
+            show_details: false,
+            show_help: false,
+            help_scroll: 0,
+            album_art: AlbumArt::default(),
+            scan_progress: None,
+            seek_prompt: None,
+            save_prompt: None,
+            queue_message: None,
+            last_saved: Instant::now(),
+            search: None,
+            keymap,
+            pending_keys: Vec::new(),
+            progress_area: Rect::default(),
+            queue_area: Rect::default(),
+            player_area: Rect::default(),
+            tree_area: Rect::default(),
+            last_click: None,
+            seek_drag: None,
         }
     }
 }
 
 impl<'a> App<'a> {
-    fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
-        self.library.update_tree_entries();
+    fn run(mut self, mut terminal: DefaultTerminal, events: Events) -> Result<()> {
+        let result = self.event_loop(&mut terminal, events);
+        // losing the saved session isn't worth failing the exit over
+        let _ = self.session_state().save();
+        result
+    }
+
+    fn session_state(&self) -> SessionState {
+        SessionState {
+            volume: self.player.volume(),
+            muted: self.player.is_muted(),
+            queue: self.queue.songs.clone(),
+            current: self.queue.current,
+            position: if self.player.is_playing() { self.player.elapsed() } else { Duration::ZERO },
+            selected: self.queue.state.selected(),
+            history: self.queue.history.clone(),
+            repeat: self.queue.repeat,
+            shuffle: self.queue.shuffle.clone(),
+            tree_opened: self.tree_state.opened().iter().cloned().collect(),
+            tree_selected: self.tree_state.selected().to_vec(),
+            sort: self.library.sort,
+            view: self.library.view,
+        }
+    }
+
+    /// Puts the queue, player and tree back the way `state` has them, with the current track paused at the saved
+    /// position. Indices that don't fit the saved queue (a hand-edited file, say) are dropped
+    fn restore_session(&mut self, state: SessionState) {
+        self.player.set_volume(state.volume);
+        self.player.set_muted(state.muted);
+        let len = state.queue.len();
+        self.queue.songs = state.queue;
+        self.queue.history = state.history.into_iter().filter(|i| *i < len).collect();
+        self.queue.repeat = state.repeat;
+        self.queue.shuffle = state.shuffle;
+        let order_fits = self.queue.shuffle.as_ref().is_none_or(|shuffle| {
+            let mut order = shuffle.order.clone();
+            order.sort_unstable();
+            order.into_iter().eq(0..len)
+        });
+        if !order_fits { self.queue.reshuffle(&[]); }
+        self.queue.state.select(state.selected.filter(|i| *i < len));
+        if let Some(current) = state.current.filter(|i| *i < len) {
+            self.player.restore(&mut self.queue, current, state.position);
+        }
+        for opened in state.tree_opened {
+            self.tree_state.open(opened);
+        }
+        self.tree_state.select(state.tree_selected);
+        self.library.sort = state.sort;
+        self.library.view = state.view;
+        self.last_saved = Instant::now();
+    }
+
+    /// Saves the session every `SAVE_INTERVAL`, so a crash or a killed terminal doesn't lose all of it
+    fn autosave(&mut self) {
+        if self.last_saved.elapsed() < SAVE_INTERVAL { return; }
+        let _ = self.session_state().save();
+        self.last_saved = Instant::now();
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal, events: Events) -> Result<()> {
+        Library::scan_in_background(self.library.config.clone(), events.sender());
+        self.scan_progress = Some(0);
+        let mut redraw = true;
         while !self.should_exit {
-            terminal.draw(|frame| frame.render_widget(&mut self, frame.area()))?;
-            if let Event::Key(key) = event::read()? {
-                self.handle_key(key);
-            };
+            if redraw {
+                terminal.draw(|frame| frame.render_widget(&mut *self, frame.area()))?;
+            }
+            // block until something happens, then take in whatever else piled up meanwhile before drawing again
+            let mut event = Some(events.next()?);
+            redraw = false;
+            while let Some(e) = event {
+                redraw |= self.handle_event(e);
+                event = events.try_next();
+            }
         }
         Ok(())
     }
 
+    /// Reacts to one event and says whether the screen needs to be redrawn because of it
+    fn handle_event(&mut self, event: AppEvent) -> bool {
+        match event {
+            AppEvent::Input(Event::Key(key)) => {
+                self.handle_key(key);
+                true
+            }
+            AppEvent::Input(Event::Mouse(mouse)) => self.handle_mouse(mouse),
+            AppEvent::Input(Event::Resize(_, _)) => true,
+            AppEvent::Input(_) => false,
+            // only the progress display changes on its own, and only while actually playing
+            AppEvent::Tick => {
+                self.autosave();
+                self.player.is_playing() && !self.player.is_paused()
+            }
+            AppEvent::Player => {
+                self.handle_player_events();
+                true
+            }
+            AppEvent::Task(TaskMessage::ScanProgress(read)) => {
+                self.scan_progress = Some(read);
+                true
+            }
+            AppEvent::Task(TaskMessage::ScanFinished(mut library)) => {
+                // the scan started out with the default view and order, not the ones picked since
+                if (library.view, library.sort) != (self.library.view, self.library.sort) {
+                    library.view = self.library.view;
+                    library.sort = self.library.sort;
+                    library.update_tree_entries();
+                }
+                self.library = *library;
+                self.scan_progress = None;
+                // results point into the old song list
+                if let Some(search) = self.search.as_mut() { search.update(&self.library.songs); }
+                self.show_skipped = !self.library.skipped.is_empty();
+                true
+            }
+        }
+    }
+
     fn handle_key(&mut self, key: KeyEvent) {
         if key.kind != KeyEventKind::Press { return; }
-        if key.code == KeyCode::Char('q') && key.modifiers == KeyModifiers::ALT { self.should_exit = true }
-        if key.modifiers == KeyModifiers::SHIFT && (key.code == KeyCode::Char('N') || key.code == KeyCode::Char('N')) { self.skip_one(); }
+        if self.show_skipped {
+            // the panel sits on top of everything, so it swallows keys until it is dismissed
+            if key.code == KeyCode::Esc || key.code == KeyCode::Enter { self.show_skipped = false; }
+            return;
+        }
+        if self.show_details {
+            match key.code {
+                KeyCode::Esc | KeyCode::Enter => self.show_details = false,
+                // whatever opened the popup closes it again
+                _ => {
+                    let action = self.keymap.resolve(self.focus_context(), &mut self.pending_keys, KeyChord::from(key));
+                    if action == Some(Action::Details) { self.show_details = false; }
+                }
+            }
+            return;
+        }
+        if self.show_help {
+            self.handle_help_key(key);
+            return;
+        }
+        if self.seek_prompt.is_some() {
+            self.handle_seek_prompt_key(key);
+            return;
+        }
+        self.queue_message = None;
+        if self.save_prompt.is_some() {
+            self.handle_save_prompt_key(key);
+            return;
+        }
+        if self.search.is_some() {
+            self.handle_search_key(key);
+            return;
+        }
+        let context = self.focus_context();
+        if let Some(action) = self.keymap.resolve(context, &mut self.pending_keys, KeyChord::from(key)) {
+            self.run_action(action, context);
+        }
+    }
+
+    fn focus_context(&self) -> Context {
+        match self.focus {
+            FOCUS_ID_QUEUE => Context::Queue,
+            FOCUS_ID_TREE => Context::Tree,
+            _ => Context::Player,
+        }
+    }
+
+    fn handle_help_key(&mut self, key: KeyEvent) {
         match key.code {
-            KeyCode::Char('t') => self.focus = FOCUS_ID_TREE,
-            KeyCode::Char('p') => self.focus = FOCUS_ID_PLAYER,
-            KeyCode::Char('q') => self.focus = FOCUS_ID_QUEUE,
+            KeyCode::Esc | KeyCode::Enter => self.show_help = false,
+            KeyCode::Down => self.help_scroll = self.help_scroll.saturating_add(1),
+            KeyCode::Up => self.help_scroll = self.help_scroll.saturating_sub(1),
+            // whatever opened the overlay closes it again
+            _ => if self.keymap.resolve(self.focus_context(), &mut self.pending_keys, KeyChord::from(key)) == Some(Action::Help) {
+                self.show_help = false;
+            },
+        }
+    }
+
+    /// Does what a key binding stands for. Actions that exist in several panes (moving the selection, ...) act on
+    /// the one that has focus
+    fn run_action(&mut self, action: Action, context: Context) {
+        match (action, context) {
+            (Action::Quit, _) => self.should_exit = true,
+            (Action::Help, _) => {
+                self.show_help = true;
+                self.help_scroll = 0;
+            }
+            (Action::FocusTree, _) => self.focus = FOCUS_ID_TREE,
+            (Action::FocusPlayer, _) => self.focus = FOCUS_ID_PLAYER,
+            (Action::FocusQueue, _) => self.focus = FOCUS_ID_QUEUE,
+            (Action::Search, _) => self.search = Some(Search::default()),
+            (Action::Next, _) => self.skip_one(),
+            (Action::Previous, _) => self.player.previous(&mut self.queue),
+            (Action::CycleRepeat, _) => self.cycle_repeat(),
+            (Action::ToggleShuffle, _) => self.toggle_shuffle(),
+            (Action::VolumeDown, _) => self.player.change_volume(-VOLUME_STEP),
+            (Action::VolumeUp, _) => self.player.change_volume(VOLUME_STEP),
+            (Action::VolumeDownFine, _) => self.player.change_volume(-VOLUME_STEP_FINE),
+            (Action::VolumeUpFine, _) => self.player.change_volume(VOLUME_STEP_FINE),
+            (Action::ToggleMute, _) => self.player.set_muted(!self.player.is_muted()),
+            (Action::TogglePause, _) => self.toggle_playback(),
+
+            (Action::Down, Context::Queue) => self.select_next(),
+            (Action::Up, Context::Queue) => self.select_previous(),
+            (Action::First, Context::Queue) => self.queue.state.select_first(),
+            (Action::Last, Context::Queue) => self.queue.state.select_last(),
+            (Action::Play, _) => self.play(),
+            (Action::MoveDown, _) => self.edit_queue(|q| q.move_selected(1)),
+            (Action::MoveUp, _) => self.edit_queue(|q| q.move_selected(-1)),
+            (Action::Deselect, _) => self.select_none(),
+            (Action::Mark, _) => self.queue.toggle_mark(),
+            (Action::Remove, _) => self.edit_queue(|q| { q.remove_selected(); }),
+            (Action::Cut, _) => self.edit_queue(Queue::cut_selected),
+            (Action::Copy, _) => self.queue.copy_selected(),
+            (Action::Paste, _) => self.edit_queue(Queue::paste),
+            (Action::PlayNext, _) => self.edit_queue(Queue::play_selected_next),
+            (Action::Clear, _) => self.edit_queue(Queue::clear),
+            (Action::Undo, _) => self.edit_queue(|q| { q.undo(); }),
+            (Action::SavePlaylist, _) if !self.queue.songs.is_empty() => self.save_prompt = Some(String::new()),
+            (Action::Details, _) => self.show_details = self.details_song().is_some(),
+
+            (Action::Down, _) => {self.tree_state.key_down();},
+            (Action::Up, _) => {self.tree_state.key_up();},
+            (Action::First, _) => {self.tree_state.select_first();},
+            (Action::Last, _) => {self.tree_state.select_last();},
+            (Action::Expand, _) => {self.tree_state.key_right();},
+            (Action::Collapse, _) => {self.tree_state.key_left();},
+            (Action::Enqueue, _) => self.enqueue_selected(Enqueue::Append),
+            (Action::EnqueueNext, _) => self.enqueue_selected(Enqueue::Next),
+            (Action::ReplaceQueue, _) => self.enqueue_selected(Enqueue::Replace),
+            (Action::CycleSort, _) => self.library.cycle_sort(),
+            (Action::CycleView, _) => self.library.cycle_view(),
+
+            (Action::SeekBack, _) => self.seek_by(-5.),
+            (Action::SeekForward, _) => self.seek_by(5.),
+            (Action::SeekBackLong, _) => self.seek_by(-30.),
+            (Action::SeekForwardLong, _) => self.seek_by(30.),
+            (Action::SeekTenths(tenths), _) => { let _ = self.player.seek_to_fraction(tenths as f64 / 10.); },
+            (Action::SeekPrompt, _) => self.seek_prompt = Some(String::new()),
             _ => {}
         }
+    }
 
-        match self.focus {
-            FOCUS_ID_QUEUE => match key.code {
-                KeyCode::Enter => self.play(),
-                KeyCode::Char(' ') => self.toggle_playback(),
-                KeyCode::Down => self.select_next(),
-                KeyCode::Up => self.select_previous(),
-                KeyCode::Esc => self.select_none(),
-                _ => {}
+    fn handle_seek_prompt_key(&mut self, key: KeyEvent) {
+        let Some(input) = self.seek_prompt.as_mut() else { return };
+        match key.code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => { input.pop(); },
+            KeyCode::Enter => {
+                if let Some(target) = parse_seek_target(input, self.player.total()) {
+                    let _ = self.player.seek(target);
+                }
+                self.seek_prompt = None;
             },
-            FOCUS_ID_TREE => match key.code {
-                KeyCode::Down => {self.tree_state.key_down();},
-                KeyCode::Up => {self.tree_state.key_up();},
-                KeyCode::Right => {self.tree_state.key_right();},
-                KeyCode::Left => {self.tree_state.key_left();},
-                KeyCode::Enter => {
-                    let selected = self.tree_state.selected().get(0);
-                    // self.library.tree_entries
-                    print!("{}", selected.unwrap())
-                    // self.queue.push_from_path(selected.unwrap());
-                },
-                _ => {}
+            KeyCode::Esc => self.seek_prompt = None,
+            _ => {}
+        }
+    }
+
+    fn handle_save_prompt_key(&mut self, key: KeyEvent) {
+        let Some(input) = self.save_prompt.as_mut() else { return };
+        match key.code {
+            // the name becomes the file name
+            KeyCode::Char(c) if !std::path::is_separator(c) => input.push(c),
+            KeyCode::Backspace => { input.pop(); },
+            KeyCode::Enter => {
+                let name = input.trim().to_owned();
+                if name.is_empty() || name.starts_with('.') { return; }
+                self.queue_message = Some(match self.library.save_playlist(&name, self.queue.songs.clone()) {
+                    Ok(path) => format!("saved {}", path.display()),
+                    Err(err) => format!("saving failed: {}", err),
+                });
+                self.save_prompt = None;
             },
+            KeyCode::Esc => self.save_prompt = None,
             _ => {}
         }
     }
 
+    fn handle_search_key(&mut self, key: KeyEvent) {
+        let Some(search) = self.search.as_mut() else { return };
+        match key.code {
+            KeyCode::Char('t') if key.modifiers == KeyModifiers::CONTROL => self.show_search_result_in_tree(),
+            KeyCode::Char(c) => {
+                search.query.push(c);
+                search.update(&self.library.songs);
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.update(&self.library.songs);
+            }
+            KeyCode::Down => search.state.select_next(),
+            KeyCode::Up => search.state.select_previous(),
+            KeyCode::Enter => self.play_search_result(),
+            KeyCode::Tab => {
+                if let Some(result) = search.selected() {
+                    self.queue.append(vec![self.library.songs[result.song].clone()]);
+                    self.player.queue_changed(&mut self.queue);
+                }
+            }
+            KeyCode::Esc => self.search = None,
+            _ => {}
+        }
+    }
+
+    /// Puts the song picked in the search right after the current track and plays it
+    fn play_search_result(&mut self) {
+        let Some(result) = self.search.as_ref().and_then(Search::selected) else { return };
+        let at = self.queue.current.map_or(0, |c| c + 1);
+        self.queue.insert_next(vec![self.library.songs[result.song].clone()]);
+        self.player.play(&mut self.queue, at);
+        self.search = None;
+    }
+
+    /// Closes the search and selects the picked song in the library tree, opening the nodes above it
+    fn show_search_result_in_tree(&mut self) {
+        let Some(result) = self.search.as_ref().and_then(Search::selected) else { return };
+        let path = self.library.tree_path(&self.library.songs[result.song]);
+        for depth in 1..path.len() {
+            self.tree_state.open(path[..depth].to_vec());
+        }
+        self.tree_state.select(path);
+        self.tree_state.scroll_selected_into_view();
+        self.focus = FOCUS_ID_TREE;
+        self.search = None;
+    }
+
+    /// Clicking or dragging on the progress bar seeks, clicking a pane focuses it and acts on what is under the
+    /// pointer, the wheel scrolls the list it is over. Returns whether the screen needs a redraw
+    fn handle_mouse(&mut self, mouse: MouseEvent) -> bool {
+        // popups sit on top of the panes, the ones that scroll take the wheel and the panes get nothing
+        if self.show_skipped || self.show_details || self.seek_prompt.is_some() || self.save_prompt.is_some() { return false; }
+        if self.show_help {
+            match mouse.kind {
+                MouseEventKind::ScrollDown => self.help_scroll = self.help_scroll.saturating_add(SCROLL_STEP),
+                MouseEventKind::ScrollUp => self.help_scroll = self.help_scroll.saturating_sub(SCROLL_STEP),
+                _ => return false,
+            }
+            return true;
+        }
+        if let Some(search) = self.search.as_mut() {
+            match mouse.kind {
+                MouseEventKind::ScrollDown => search.state.scroll_down_by(SCROLL_STEP),
+                MouseEventKind::ScrollUp => search.state.scroll_up_by(SCROLL_STEP),
+                _ => return false,
+            }
+            return true;
+        }
+
+        let position = Position::new(mouse.column, mouse.row);
+        let area = self.progress_area;
+        let fraction = (mouse.column.saturating_sub(area.x) as f64 / area.width.max(1) as f64).clamp(0., 1.);
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) if area.contains(position) => {
+                self.focus = FOCUS_ID_PLAYER;
+                self.seek_drag = Some(fraction);
+            }
+            MouseEventKind::Drag(MouseButton::Left) if self.seek_drag.is_some() => self.seek_drag = Some(fraction),
+            MouseEventKind::Up(MouseButton::Left) if self.seek_drag.is_some() => {
+                if let Some(fraction) = self.seek_drag.take() {
+                    let _ = self.player.seek_to_fraction(fraction);
+                }
+            },
+            MouseEventKind::Down(MouseButton::Left) if self.queue_area.contains(position) => {
+                self.focus = FOCUS_ID_QUEUE;
+                self.click_queue(position);
+            }
+            MouseEventKind::Down(MouseButton::Left) if self.tree_area.contains(position) => {
+                self.focus = FOCUS_ID_TREE;
+                // selects the node, or opens/closes it when it already was selected
+                self.tree_state.click_at(position);
+            }
+            MouseEventKind::Down(MouseButton::Left) if self.player_area.contains(position) => self.focus = FOCUS_ID_PLAYER,
+            MouseEventKind::ScrollDown if self.queue_area.contains(position) => self.queue.state.scroll_down_by(SCROLL_STEP),
+            MouseEventKind::ScrollUp if self.queue_area.contains(position) => self.queue.state.scroll_up_by(SCROLL_STEP),
+            MouseEventKind::ScrollDown if self.tree_area.contains(position) => {self.tree_state.scroll_down(SCROLL_STEP as usize);},
+            MouseEventKind::ScrollUp if self.tree_area.contains(position) => {self.tree_state.scroll_up(SCROLL_STEP as usize);},
+            _ => return false,
+        }
+        true
+    }
+
+    /// Selects the queue entry at `position`, a second click on it in quick succession plays it
+    fn click_queue(&mut self, position: Position) {
+        let inner = self.queue_area.inner(Margin::new(1, 1));
+        if !inner.contains(position) { return; }
+        let index = self.queue.state.offset() + ((position.y - inner.y) / QUEUE_ITEM_HEIGHT) as usize;
+        if index >= self.queue.songs.len() { return; }
+
+        let double = self.last_click.is_some_and(|(at, i)| i == index && at.elapsed() <= DOUBLE_CLICK);
+        self.queue.state.select(Some(index));
+        if double {
+            self.last_click = None;
+            self.play();
+        } else {
+            self.last_click = Some((Instant::now(), index));
+        }
+    }
+
+    fn seek_by(&mut self, seconds: f64) {
+        // a track that can't seek just keeps playing where it is
+        let _ = self.player.seek_by(seconds);
+    }
+
     fn select_none(&mut self) {
-        self.queue.state.select(None);
+        // the first Esc only drops a range selection
+        if self.queue.mark.take().is_none() { self.queue.state.select(None); }
     }
 
     fn select_next(&mut self) {
@@ -121,45 +596,106 @@ impl<'a> App<'a> {
     }
 
     fn play(&mut self) {
-        let index = self.queue.state.selected().unwrap();
-        // self.queue.go_to(index);
-        // self.audio_controls.append(self.queue.songs.get(index).unwrap().get_source());
-        
-        self.audio_controls.append(
-            Decoder::new(BufReader::new(
-                File::open(self.queue.songs.get(index).unwrap().path.clone()).unwrap(),
-            ))
-            .unwrap(),
-        );
+        let Some(index) = self.queue.state.selected() else { return };
+        self.player.play(&mut self.queue, index);
+    }
+
+    /// Runs an edit on the queue and lets the player catch up with it. Taking the track being heard out of the
+    /// queue moves playback on to the entry that took its place
+    fn edit_queue(&mut self, edit: impl FnOnce(&mut Queue)) {
+        let playing = self.queue.current;
+        edit(&mut self.queue);
+        match playing {
+            Some(index) if self.queue.current.is_none() && index < self.queue.songs.len() => {
+                self.player.play(&mut self.queue, index);
+            }
+            Some(_) if self.queue.current.is_none() => self.player.stop(&mut self.queue),
+            _ => self.player.queue_changed(&mut self.queue),
+        }
+    }
+
+    fn handle_player_events(&mut self) {
+        let previous = self.queue.current;
+        for event in self.player.poll_events(&mut self.queue) {
+            match event {
+                // let the selection follow playback unless the user moved it elsewhere
+                PlayerEvent::TrackChanged(Some(index)) if self.queue.state.selected() == previous => {
+                    self.queue.state.select(Some(index));
+                }
+                PlayerEvent::TrackChanged(_) => {}
+            }
+        }
+    }
+
+    /// Puts whatever is selected in the tree (a track, a whole album or all of an artist) into the queue
+    fn enqueue_selected(&mut self, mode: Enqueue) {
+        let songs = self.library.songs_under(self.tree_state.selected());
+        if songs.is_empty() { return; }
+        match mode {
+            Enqueue::Append => self.queue.append(songs),
+            Enqueue::Next => self.queue.insert_next(songs),
+            Enqueue::Replace => {
+                self.queue.replace(songs);
+                self.player.play(&mut self.queue, 0);
+                return;
+            }
+        }
+        self.player.queue_changed(&mut self.queue);
     }
     fn skip_one(&mut self) {
-        self.audio_controls.skip_one();
+        self.player.skip(&mut self.queue);
     }
     fn toggle_playback(&mut self) {
-        if self.audio_controls.is_paused() {
-            self.audio_controls.play();
-        } else {
-            self.audio_controls.pause();
-        }
+        self.player.toggle_pause();
+    }
+    fn cycle_repeat(&mut self) {
+        self.queue.repeat = self.queue.repeat.cycle();
+        self.player.queue_changed(&mut self.queue);
+    }
+    fn toggle_shuffle(&mut self) {
+        let seed = match self.queue.shuffle {
+            Some(_) => None,
+            // a new order every time shuffle is switched on; the seed is kept, so it stays the same order after
+            None => Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64),
+        };
+        self.queue.set_shuffle(seed);
+        self.player.queue_changed(&mut self.queue);
     }
 }
 
 impl<'a> Widget for &mut App<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let [queue_area, _player_area, tree_area] = Layout::horizontal(Constraint::from_percentages([30, 40, 30]))
+        let [queue_area, player_area, tree_area] = Layout::horizontal(Constraint::from_percentages([30, 40, 30]))
         .areas(area);
 
+        self.queue_area = queue_area;
+        self.player_area = player_area;
+        self.tree_area = tree_area;
         self.render_queue(queue_area, buf);
+        self.render_player(player_area, buf);
         self.render_tree(tree_area, buf);
-        // self.render_selected_item(player_area, buf);
+        if self.search.is_some() { self.render_search(area, buf); }
+        if self.show_details { self.render_details(area, buf); }
+        if self.show_help { self.render_help(area, buf); }
+        if self.show_skipped { self.render_skipped(area, buf); }
     }
 }
 
 impl<'a> App<'a> {
     fn render_tree(&mut self, area: Rect, buf: &mut Buffer) {
+        let title = match self.scan_progress {
+            Some(read) => format!("Tree {} / scanning... {} files", self.keymap.hint(Action::FocusTree, Context::Tree), read),
+            None => format!(
+                "Tree {} / view: {}, order: {} / {} help",
+                self.keymap.hint(Action::FocusTree, Context::Tree),
+                self.library.view.label(),
+                self.library.sort.label(),
+                self.keymap.hint(Action::Help, Context::Tree),
+            ),
+        };
         let block: Block<'_> = Block::new()
         .borders(Borders::all())
-            .title("[T]ree / [ALT]+[Q] to exit")
+            .title(title)
             .border_type(BorderType::Rounded)
             .border_style(if self.focus==FOCUS_ID_TREE {Style::new().green()}else{Style::new().red()});
 
@@ -173,18 +709,37 @@ impl<'a> App<'a> {
     fn render_queue(&mut self, area: Rect, buf: &mut Buffer) {
         let block = Block::new()
             .border_type(ratatui::widgets::BorderType::Rounded)
-            .title("Playback [q]ueue")
+            .title(format!("Playback queue {}", self.keymap.hint(Action::FocusQueue, Context::Queue)))
+            .title_top(Line::from(queue_summary(&self.queue.songs)).right_aligned())
+            .title_bottom(match (&self.save_prompt, &self.queue_message) {
+                (Some(input), _) => Line::from(vec!["Save as: ".yellow(), input.clone().into(), "_".into()]),
+                (None, Some(message)) => Line::from(message.clone()),
+                (None, None) => Line::default(),
+            })
             .borders(Borders::all())
             .border_style(if self.focus==FOCUS_ID_QUEUE {Style::new().green()}else{Style::new().red()});
 
-        let items: Vec<playlist::Song> = self
+        // only worth showing while a range is being selected, a single selected entry is highlighted anyway
+        let range = self.queue.mark.and(self.queue.selected_range());
+        // inside the borders, less the highlight symbol
+        let width = area.width.saturating_sub(3) as usize;
+        let items: Vec<ListItem> = self
             .queue
             .songs
             .iter()
             .enumerate()
-            .map(|(_, s)| {
+            .map(|(i, s)| {
                 // let color = alternate_colors(i);
-                playlist::Song::from((*s).clone())
+                let duration = s.duration.map(format_duration).unwrap_or_default();
+                let title = truncate(&s.title, width.saturating_sub(duration.len() + 1));
+                let padding = width.saturating_sub(Span::raw(&title).width() + duration.len());
+                let item = ListItem::new(Text::from(vec![
+                    Line::from(vec![title.bold(), " ".repeat(padding).into(), duration.gray()]),
+                    Line::from(format!(" - {}", s.artist.as_deref().unwrap_or("Unknown Artist"))),
+                ]));
+                let item = if range.as_ref().is_some_and(|r| r.contains(&i)) { item.on_dark_gray() } else { item };
+                // mark the track that is being heard
+                if self.queue.current == Some(i) { item.green() } else { item }
             })
             .collect();
 
@@ -197,11 +752,355 @@ impl<'a> App<'a> {
         StatefulWidget::render(list, area, buf, &mut self.queue.state);
     }
 
-    // fn render_selected_item(&self, area: Rect, buf: &mut Buffer) {
-    //     let info = if let Some(_) = self.queue.state.selected() {
-    //         "Nothing selected...".to_string()
-    //     } else {
-    //         "Nothing selected...".to_string()
-    //     };
-    // }
+    /// The queue entry the details panel is about: the selected one, or the current one without a selection
+    fn details_song(&self) -> Option<&Song> {
+        self.queue.state.selected().or(self.queue.current).and_then(|i| self.queue.songs.get(i))
+    }
+
+    fn render_details(&mut self, area: Rect, buf: &mut Buffer) {
+        let Some(song) = self.details_song() else { return };
+        let [_, popup_area, _] = Layout::vertical(Constraint::from_percentages([20, 60, 20])).areas(area);
+        let [_, popup_area, _] = Layout::horizontal(Constraint::from_percentages([20, 60, 20])).areas(popup_area);
+
+        let block = Block::new()
+            .borders(Borders::all())
+            .title("Details / [Esc] to close")
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().yellow());
+
+        let unknown = || "-".to_owned();
+        let number = |num: Option<u32>, total: Option<u32>| match (num, total) {
+            (Some(num), Some(total)) => format!("{}/{}", num, total),
+            (Some(num), None) => num.to_string(),
+            _ => unknown(),
+        };
+        let rows: Vec<(&str, String)> = vec![
+            ("Title", song.title.clone()),
+            ("Artist", song.artist.clone().unwrap_or_else(unknown)),
+            ("Album artist", song.album_artist.clone().unwrap_or_else(unknown)),
+            ("Album", song.album.clone().unwrap_or_else(unknown)),
+            ("Genre", song.genre.clone().unwrap_or_else(unknown)),
+            ("Year", song.year.map_or_else(unknown, |y| y.to_string())),
+            ("Track", number(song.track_num, song.album_tracks_total)),
+            ("Disc", number(song.disc_num, song.disc_total)),
+            ("Compilation", if song.compilation { "yes" } else { "no" }.to_owned()),
+            ("", String::new()),
+            ("Duration", song.duration.map_or_else(unknown, format_duration)),
+            ("Codec", song.codec.clone().unwrap_or_else(unknown)),
+            ("Sample rate", song.sample_rate.map_or_else(unknown, |r| format!("{} Hz", r))),
+            ("Channels", song.channels.map_or_else(unknown, |c| c.to_string())),
+            ("Bitrate", song.bitrate.map_or_else(unknown, |b| format!("{} kbit/s", b))),
+            ("File", song.path.clone()),
+        ];
+        let lines: Vec<Line> = rows.into_iter()
+            .map(|(label, value)| Line::from(vec![format!("{:<13}", label).gray(), value.into()]))
+            .collect();
+
+        Clear.render(popup_area, buf);
+        Paragraph::new(lines).wrap(Wrap { trim: false }).block(block).render(popup_area, buf);
+    }
+
+    /// The bindings of the focused pane and the global ones, straight from the keymap
+    fn render_help(&mut self, area: Rect, buf: &mut Buffer) {
+        let [_, popup_area, _] = Layout::vertical(Constraint::from_percentages([10, 80, 10])).areas(area);
+        let [_, popup_area, _] = Layout::horizontal(Constraint::from_percentages([20, 60, 20])).areas(popup_area);
+
+        let context = self.focus_context();
+        let block = Block::new()
+            .borders(Borders::all())
+            .title("Keys / [Esc] to close")
+            .title_bottom("[↑↓] scroll")
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().yellow());
+
+        let sections = [(context, self.keymap.entries(context)), (Context::Global, self.keymap.entries(Context::Global))];
+        let width = sections.iter().flat_map(|(_, e)| e.iter().map(|(keys, _)| keys.len())).max().unwrap_or(0);
+        let mut lines: Vec<Line> = Vec::new();
+        for (context, entries) in sections {
+            if !lines.is_empty() { lines.push(Line::default()); }
+            let heading = match context {
+                Context::Global => "Everywhere".to_owned(),
+                other => format!("In the {}", other.name()),
+            };
+            lines.push(Line::from(heading.bold()));
+            for (keys, action) in entries {
+                lines.push(Line::from(vec![format!("  {:<width$}  ", keys, width = width).yellow(), action.description().into()]));
+            }
+        }
+
+        let inner_height = block.inner(popup_area).height;
+        self.help_scroll = self.help_scroll.min((lines.len() as u16).saturating_sub(inner_height));
+        Clear.render(popup_area, buf);
+        Paragraph::new(lines).scroll((self.help_scroll, 0)).block(block).render(popup_area, buf);
+    }
+
+    fn render_skipped(&mut self, area: Rect, buf: &mut Buffer) {
+        let [_, popup_area, _] = Layout::vertical(Constraint::from_percentages([20, 60, 20])).areas(area);
+        let [_, popup_area, _] = Layout::horizontal(Constraint::from_percentages([10, 80, 10])).areas(popup_area);
+
+        let block = Block::new()
+            .borders(Borders::all())
+            .title(format!("{} files skipped / [Esc] to dismiss", self.library.skipped.len()))
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().yellow());
+
+        let items: Vec<ListItem> = self.library.skipped
+            .iter()
+            .map(|s| ListItem::new(Text::from(vec![
+                Line::from(s.path.clone().bold()),
+                Line::from(format!("  {}", s.error)).gray(),
+            ])))
+            .collect();
+
+        Clear.render(popup_area, buf);
+        Widget::render(List::new(items).block(block), popup_area, buf);
+    }
+
+    fn render_search(&mut self, area: Rect, buf: &mut Buffer) {
+        let Some(search) = self.search.as_mut() else { return };
+        let [_, popup_area, _] = Layout::vertical(Constraint::from_percentages([15, 70, 15])).areas(area);
+        let [_, popup_area, _] = Layout::horizontal(Constraint::from_percentages([15, 70, 15])).areas(popup_area);
+
+        let block = Block::new()
+            .borders(Borders::all())
+            .title(Line::from(vec!["Search: ".yellow(), search.query.clone().into(), "_".into()]))
+            .title_bottom(format!(
+                "{} found / [Enter] play, [Tab] enqueue, [Ctrl]+[T] show in tree, [Esc] close",
+                search.results.len(),
+            ))
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().yellow());
+
+        let hit = Style::new().yellow().bold();
+        let items: Vec<ListItem> = search.results
+            .iter()
+            .map(|result| {
+                let song = &self.library.songs[result.song];
+                let mut title = highlighted(&song.title, &result.title_hits, Style::new().bold(), hit);
+                title.push(" - ".into());
+                title.extend(highlighted(song.artist.as_deref().unwrap_or(""), &result.artist_hits, Style::new(), hit));
+                let album = highlighted(song.album.as_deref().unwrap_or(""), &result.album_hits, Style::new().gray(), hit);
+                ListItem::new(Text::from(vec![Line::from(title), Line::from(album)]))
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_style(SELECTED_STYLE)
+            .highlight_symbol(">")
+            .highlight_spacing(HighlightSpacing::Always);
+
+        Clear.render(popup_area, buf);
+        StatefulWidget::render(list, popup_area, buf, &mut search.state);
+    }
+
+    fn render_player(&mut self, area: Rect, buf: &mut Buffer) {
+        let block = Block::new()
+            .borders(Borders::all())
+            .title(format!(
+                "Now playing {} / {} help",
+                self.keymap.hint(Action::FocusPlayer, Context::Player),
+                self.keymap.hint(Action::Help, Context::Player),
+            ))
+            .border_type(BorderType::Rounded)
+            .border_style(if self.focus==FOCUS_ID_PLAYER {Style::new().green()}else{Style::new().red()});
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let song = self.queue.current.and_then(|i| self.queue.songs.get(i));
+        let has_art = song.is_some_and(|s| self.album_art.has_art(&s.path));
+        let [art_area, info_area, progress_area, status_area] = Layout::vertical([
+            Constraint::Fill(if has_art { 1 } else { 0 }),
+            if has_art { Constraint::Length(7) } else { Constraint::Fill(1) },
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ]).areas(inner);
+
+        if let Some(art) = song.and_then(|s| self.album_art.get(&s.path, art_area.width, art_area.height)) {
+            Paragraph::new(art.clone()).centered().render(art_area, buf);
+        }
+        let info: Text = match song {
+            Some(song) => {
+                let unknown = || "-".to_owned();
+                let mut track = match (song.track_num, song.album_tracks_total) {
+                    (Some(num), Some(total)) => format!("{}/{}", num, total),
+                    (Some(num), None) => num.to_string(),
+                    _ => unknown(),
+                };
+                // only worth mentioning for albums spanning more than one disc
+                match (song.disc_num, song.disc_total) {
+                    (Some(num), Some(total)) if total > 1 => track.push_str(&format!(", disc {}/{}", num, total)),
+                    (Some(num), None) if num > 1 => track.push_str(&format!(", disc {}", num)),
+                    _ => {}
+                }
+                Text::from(vec![
+                    Line::from(song.title.clone().bold()),
+                    Line::from(song.artist.clone().unwrap_or_else(unknown)),
+                    Line::from(""),
+                    Line::from(vec!["Album: ".gray(), song.album.clone().unwrap_or_else(unknown).into()]),
+                    Line::from(vec!["By:    ".gray(), album_artist_of(song).map_or_else(unknown, str::to_owned).into()]),
+                    Line::from(vec!["Year:  ".gray(), song.year.map_or_else(unknown, |y| y.to_string()).into()]),
+                    Line::from(vec!["Track: ".gray(), track.into()]),
+                ])
+            }
+            None => Text::from("Nothing playing...".gray()),
+        };
+        Paragraph::new(info).wrap(Wrap { trim: false }).render(info_area, buf);
+
+        let total = self.player.total();
+        // while the bar is being dragged it shows where playback will jump to
+        let elapsed = match (self.seek_drag, total) {
+            (Some(fraction), Some(total)) => total.mul_f64(fraction),
+            _ => self.player.elapsed(),
+        };
+        let ratio = total
+            .filter(|t| !t.is_zero())
+            .map_or(0., |t| (elapsed.as_secs_f64() / t.as_secs_f64()).clamp(0., 1.));
+        self.progress_area = progress_area;
+        LineGauge::default()
+            .filled_style(Style::new().green())
+            .unfilled_style(Style::new().dark_gray())
+            .label(format!(
+                "{} / {}",
+                format_duration(elapsed),
+                total.map_or_else(|| "--:--".to_owned(), format_duration)
+            ))
+            .ratio(ratio)
+            .render(progress_area, buf);
+
+        let state = match (self.player.is_playing(), self.player.is_paused()) {
+            (false, _) => "■ Stopped".gray(),
+            (true, true) => "⏸ Paused".yellow(),
+            (true, false) => "▶ Playing".green(),
+        };
+        if let Some(input) = &self.seek_prompt {
+            Line::from(vec!["Seek to (1:23, 45%): ".yellow(), input.clone().into(), "_".into()]).render(status_area, buf);
+            return;
+        }
+        let repeat = match self.queue.repeat {
+            RepeatMode::Off => "⟳ off".dark_gray(),
+            RepeatMode::All => "⟳ all".green(),
+            RepeatMode::One => "⟳ one".green(),
+        };
+        let shuffle = if self.queue.shuffle.is_some() { "⤮ on".green() } else { "⤮ off".dark_gray() };
+        let mute_hint = self.keymap.hint(Action::ToggleMute, Context::Player);
+        Line::from(vec![
+            state,
+            "  ".into(),
+            repeat,
+            "  ".into(),
+            shuffle,
+            "  ".into(),
+            volume_indicator(self.player.volume(), self.player.is_muted(), &mute_hint),
+        ]).render(status_area, buf);
+    }
+}
+
+/// `text` in `style`, with the chars at the positions in `hits` in `hit_style` instead
+fn highlighted(text: &str, hits: &[usize], style: Style, hit_style: Style) -> Vec<Span<'static>> {
+    let mut spans: Vec<Span<'static>> = Vec::new();
+    let mut run = String::new();
+    let mut run_is_hit = false;
+    for (i, c) in text.chars().enumerate() {
+        let is_hit = hits.contains(&i);
+        if is_hit != run_is_hit && !run.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut run), if run_is_hit { hit_style } else { style }));
+        }
+        run_is_hit = is_hit;
+        run.push(c);
+    }
+    if !run.is_empty() { spans.push(Span::styled(run, if run_is_hit { hit_style } else { style })); }
+    spans
+}
+
+/// "Vol ▮▮▮▮▮▯▯▯▯▯  50%", dimmed and labelled when muted, with `unmute_hint` (the key for unmuting) after it
+fn volume_indicator(volume: f32, muted: bool, unmute_hint: &str) -> ratatui::text::Span<'static> {
+    let filled = (volume * 10.).round() as usize;
+    let bar = format!("{}{}", "▮".repeat(filled), "▯".repeat(10 - filled));
+    if muted {
+        format!("Vol {} muted {}", bar, unmute_hint).trim_end().to_owned().dark_gray()
+    } else {
+        format!("Vol {} {:>3}%", bar, (volume * 100.).round() as i32).into()
+    }
+}
+
+/// "12 tracks, 47:11", with a "+" when some lengths aren't known
+fn queue_summary(songs: &[Song]) -> String {
+    let total: Duration = songs.iter().filter_map(|s| s.duration).sum();
+    let incomplete = if songs.iter().any(|s| s.duration.is_none()) { "+" } else { "" };
+    format!("{} tracks, {}{}", songs.len(), format_duration(total), incomplete)
+}
+
+/// `text` cut down to at most `width` columns, ending in "…" when it had to be cut
+fn truncate(text: &str, width: usize) -> String {
+    if Span::raw(text).width() <= width { return text.to_owned(); }
+    let mut cut = String::new();
+    for c in text.chars() {
+        if Span::raw(format!("{}{}…", cut, c)).width() > width { break; }
+        cut.push(c);
+    }
+    if width > 0 { cut.push('…'); }
+    cut
+}
+
+/// `m:ss`, or `h:mm:ss` for anything an hour or longer
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_indicators() {
+        assert_eq!(volume_indicator(0.5, false, "[m]").content, "Vol ▮▮▮▮▮▯▯▯▯▯  50%");
+        assert_eq!(volume_indicator(1., true, "[alt+m]").content, "Vol ▮▮▮▮▮▮▮▮▮▮ muted [alt+m]");
+        // mute unbound
+        assert_eq!(volume_indicator(0., true, "").content, "Vol ▯▯▯▯▯▯▯▯▯▯ muted");
+
+        let keymap = Keymap::new(&toml::from_str("[global]\ntoggle_mute = \"alt+m\"").unwrap()).unwrap();
+        assert_eq!(keymap.hint(Action::ToggleMute, Context::Player), "[alt+m]");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::ZERO), "0:00");
+        assert_eq!(format_duration(Duration::from_millis(65_999)), "1:05");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59:59");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1:00:00");
+        assert_eq!(format_duration(Duration::from_secs(36_061)), "10:01:01");
+    }
+
+    #[test]
+    fn queue_summaries() {
+        let timed = |secs| {
+            let mut song = Song::untagged("/music/a.mp3");
+            song.duration = Some(Duration::from_secs(secs));
+            song
+        };
+        assert_eq!(queue_summary(&[]), "0 tracks, 0:00");
+        assert_eq!(queue_summary(&[timed(1800), timed(1800)]), "2 tracks, 1:00:00");
+        assert_eq!(queue_summary(&[timed(90), Song::untagged("/music/b.mp3")]), "2 tracks, 1:30+");
+    }
+
+    #[test]
+    fn truncating() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exact", 5), "exact");
+        assert_eq!(truncate("too long", 5), "too …");
+        assert_eq!(truncate("abc", 1), "…");
+        assert_eq!(truncate("abc", 0), "");
+        // wide characters take two columns, the ellipsis one
+        assert_eq!(truncate("日本語", 6), "日本語");
+        assert_eq!(truncate("日本語", 5), "日本…");
+        assert_eq!(truncate("日本語", 4), "日…");
+        assert_eq!(truncate("日本語", 2), "…");
+        assert_eq!(truncate("a日本", 3), "a…");
+    }
 }
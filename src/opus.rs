@@ -0,0 +1,103 @@
+use std::sync::Mutex;
+
+use audiopus::{coder::{Decoder as LibOpus, GenericCtl}, packet::Packet as OpusPacket, Channels as OpusChannels, MutSignals, SampleRate};
+use symphonia::core::{
+    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec},
+    codecs::{CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS},
+    errors::{decode_error, unsupported_error, Result},
+    formats::Packet,
+    support_codec,
+};
+
+/// Longest frame an Opus packet can decode to: 120 ms at 48 kHz
+const MAX_FRAMES: usize = 5760;
+
+/// Opus through libopus, registered with symphonia like its own decoders (see [`crate::formats::codecs`]).
+/// Handles the mono and stereo streams of channel mapping family 0, which is what music is encoded as
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus' decoder may move between threads but not be shared, symphonia wants both
+    decoder: Mutex<LibOpus>,
+    channels: usize,
+    /// the header's output gain as a factor
+    gain: f32,
+    /// interleaved output of libopus
+    samples: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _: &DecoderOptions) -> Result<Self> {
+        // the OpusHead packet: magic, version, channel count, pre-skip, input rate, output gain, mapping family
+        let head = params.extra_data.as_deref().unwrap_or_default();
+        if head.len() < 19 || &head[..8] != b"OpusHead" { return decode_error("opus: missing header"); }
+        if head[18] != 0 { return unsupported_error("opus: only channel mapping family 0 is supported"); }
+        let (channels, opus_channels) = match head[9] {
+            1 => (1, OpusChannels::Mono),
+            2 => (2, OpusChannels::Stereo),
+            _ => return unsupported_error("opus: unsupported channel count"),
+        };
+        let gain_db = i16::from_le_bytes([head[16], head[17]]) as f32 / 256.;
+        let Some(layout) = params.channels else { return decode_error("opus: missing channels") };
+        let decoder = match LibOpus::new(SampleRate::Hz48000, opus_channels) {
+            Ok(decoder) => decoder,
+            Err(_) => return decode_error("opus: failed to create the decoder"),
+        };
+
+        Ok(Self {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels,
+            gain: 10f32.powf(gain_db / 20.),
+            samples: vec![0.; MAX_FRAMES * channels],
+            buf: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(48_000, layout)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        // after a seek, so the next packet isn't predicted from audio before it
+        if let Ok(decoder) = self.decoder.get_mut() { let _ = decoder.reset_state(); }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+        let decoder = match self.decoder.get_mut() {
+            Ok(decoder) => decoder,
+            Err(_) => return decode_error("opus: decoder poisoned"),
+        };
+        let (Ok(input), Ok(output)) = (OpusPacket::try_from(packet.buf()), MutSignals::try_from(&mut self.samples[..])) else {
+            return decode_error("opus: empty packet");
+        };
+        let frames = match decoder.decode_float(Some(input), output, false) {
+            Ok(frames) => frames,
+            Err(_) => return decode_error("opus: invalid packet"),
+        };
+
+        self.buf.render_reserved(Some(frames));
+        for channel in 0..self.channels {
+            let plane = self.buf.chan_mut(channel);
+            for (frame, sample) in plane.iter_mut().enumerate() {
+                *sample = self.samples[frame * self.channels + channel] * self.gain;
+            }
+        }
+        // the pre-skip at the start and the padding of the last page, as worked out by the Ogg reader
+        self.buf.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
@@ -1,13 +1,514 @@
-use std::rc::Rc;
-
-use ratatui::{layout::{Constraint, Direction, Layout, Rect}, Frame};
-
-fn get_layout(f: &Frame) -> Rc<[Rect]> {
-    Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Percentage(60),
-            Constraint::Percentage(100 - 60),
-        ])
-        .split(f.area())
-}
\ No newline at end of file
+use std::{
+    sync::{atomic::{AtomicU32, AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::{source::SeekError, OutputStream, Sink, Source};
+
+use crate::{events::AppEvent, playlist::{Queue, SongError}};
+
+/// Samples handed to the output between parameter checks when a source can't say how long its frames are,
+/// same as rodio's own queue uses
+const FRAME_LEN_FALLBACK: usize = 512;
+
+/// Loudness range the volume slider spans; the bottom of the slider is this many dB below full volume
+const VOLUME_RANGE_DB: f32 = 50.;
+
+/// How far into a track "previous" restarts it rather than going back to the track before
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
+pub type TrackSource = Box<dyn Source<Item = f32> + Send>;
+
+/// What the player tells the UI about
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlayerEvent {
+    /// playback moved on to the queue entry at this index, or stopped at the end of the queue
+    TrackChanged(Option<usize>),
+}
+
+/// State the audio thread and the player share for one run of playback
+struct Shared {
+    /// the track that starts as soon as the current one runs out, already opened and decoding
+    next: Mutex<Option<(usize, TrackSource)>>,
+    /// samples of the current track handed to the output so far
+    samples: AtomicU64,
+    sample_rate: AtomicU32,
+    channels: AtomicU32,
+    /// length of the current track in nanoseconds as far as the decoder knows it, 0 if it doesn't
+    total_nanos: AtomicU64,
+}
+
+/// A source that plays queue entries back to back and swaps to the preloaded next track on the very sample the
+/// current one ends, so there's no gap between them. Each swap is announced through `events`
+struct TrackChain {
+    current: Option<(usize, TrackSource)>,
+    shared: Arc<Shared>,
+    generation: u64,
+    events: Sender<(u64, PlayerEvent)>,
+    wake: Sender<AppEvent>,
+}
+
+impl TrackChain {
+    fn switch_to(&mut self, track: Option<(usize, TrackSource)>) {
+        if let Some((_, source)) = &track {
+            self.shared.sample_rate.store(source.sample_rate(), Ordering::Relaxed);
+            self.shared.channels.store(source.channels() as u32, Ordering::Relaxed);
+            self.shared.total_nanos.store(total_nanos(source), Ordering::Relaxed);
+        }
+        self.shared.samples.store(0, Ordering::Relaxed);
+        let _ = self.events.send((self.generation, PlayerEvent::TrackChanged(track.as_ref().map(|(i, _)| *i))));
+        let _ = self.wake.send(AppEvent::Player);
+        self.current = track;
+    }
+
+    /// Moves on to the preloaded next track, or to silence if there is none
+    fn advance(&mut self) {
+        let next = self.shared.next.lock().unwrap().take();
+        self.switch_to(next);
+    }
+}
+
+impl Iterator for TrackChain {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            let (_, source) = self.current.as_mut()?;
+            if let Some(sample) = source.next() {
+                self.shared.samples.fetch_add(1, Ordering::Relaxed);
+                // a track known to be over is left with its last sample rather than on the call after, so the
+                // output asks the next track, not the finished one, for the channels and rate of its next frame
+                if source.current_frame_len() == Some(0) { self.advance(); }
+                return Some(sample);
+            }
+            self.advance();
+        }
+    }
+}
+
+impl Source for TrackChain {
+    fn current_frame_len(&self) -> Option<usize> {
+        match &self.current {
+            // 0 would end the whole chain, a source only says so here when it was empty from the start
+            Some((_, source)) => source.current_frame_len().filter(|n| *n != 0).or(Some(FRAME_LEN_FALLBACK)),
+            None => Some(0),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.current.as_ref().map_or(2, |(_, s)| s.channels())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.current.as_ref().map_or(44100, |(_, s)| s.sample_rate())
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Seeks within the current track, the following ones always start from the beginning
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let Some((_, source)) = self.current.as_mut() else { return Ok(()) };
+        source.try_seek(pos)?;
+        let channels = source.channels() as u64;
+        let frames = (pos.as_secs_f64() * source.sample_rate() as f64) as u64;
+        self.shared.samples.store(frames * channels, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Plays the [`Queue`]: owns the audio output, knows which entry is being heard and keeps the following one
+/// decoded ahead of time for gapless transitions
+pub struct Player {
+    _stream: OutputStream,
+    sink: Sink,
+    shared: Option<Arc<Shared>>,
+    generation: u64,
+    sender: Sender<(u64, PlayerEvent)>,
+    events: Receiver<(u64, PlayerEvent)>,
+    /// wakes the main loop up whenever something lands in `events`
+    wake: Sender<AppEvent>,
+    /// index into the queue of the track being heard
+    current: Option<usize>,
+    /// index and path of the track sitting in `shared.next`, to notice when queue edits made it stale
+    preloaded: Option<(usize, String)>,
+    /// length of the current track as probed when it was added to the library
+    duration: Option<Duration>,
+    /// volume slider position from 0 to 1, mapped onto the output's amplitude by `slider_to_amplitude`
+    volume: f32,
+    muted: bool,
+}
+
+impl Player {
+    pub fn new(wake: Sender<AppEvent>) -> color_eyre::Result<Self> {
+        let (stream, handle) = OutputStream::try_default()?;
+        let (sender, events) = mpsc::channel();
+        Ok(Self {
+            _stream: stream,
+            sink: Sink::try_new(&handle)?,
+            shared: None,
+            generation: 0,
+            sender,
+            events,
+            wake,
+            current: None,
+            preloaded: None,
+            duration: None,
+            volume: 1.,
+            muted: false,
+        })
+    }
+
+    /// Starts playing the queue from `index`, dropping whatever was playing before. Entries that can't be opened
+    /// are passed over
+    pub fn play(&mut self, queue: &mut Queue, index: usize) {
+        self.start(queue, index, true);
+    }
+
+    fn start(&mut self, queue: &mut Queue, index: usize, record_history: bool) {
+        self.load(queue, index, record_history, Duration::ZERO);
+        // a jump by hand changes what's still to come in a shuffled queue
+        queue.reshuffle(&[]);
+        self.preload(queue);
+        self.sink.play();
+    }
+
+    /// Loads the entry at `index` paused at `position`, to pick up where an earlier session left off
+    pub fn restore(&mut self, queue: &mut Queue, index: usize, position: Duration) {
+        self.load(queue, index, false, position);
+        self.preload(queue);
+    }
+
+    /// Replaces whatever is playing by the entry at `index` (or the first one after it that opens), leaving the
+    /// output paused
+    fn load(&mut self, queue: &mut Queue, index: usize, record_history: bool, position: Duration) {
+        self.sink.clear();
+        self.generation += 1;
+        self.preloaded = None;
+
+        let Some((index, mut source)) = open_from(queue, Some(index)) else {
+            self.shared = None;
+            self.set_current(queue, None, record_history);
+            return;
+        };
+        // starting from the top is the best there is for a file that can't seek
+        let position = if source.try_seek(position).is_ok() { position } else { Duration::ZERO };
+        let frames = (position.as_secs_f64() * source.sample_rate() as f64) as u64;
+        let shared = Arc::new(Shared {
+            next: Mutex::new(None),
+            samples: AtomicU64::new(frames * source.channels() as u64),
+            sample_rate: AtomicU32::new(source.sample_rate()),
+            channels: AtomicU32::new(source.channels() as u32),
+            total_nanos: AtomicU64::new(total_nanos(&source)),
+        });
+        self.sink.append(TrackChain {
+            current: Some((index, source)),
+            shared: shared.clone(),
+            generation: self.generation,
+            events: self.sender.clone(),
+            wake: self.wake.clone(),
+        });
+        self.shared = Some(shared);
+        self.set_current(queue, Some(index), record_history);
+    }
+
+    /// Jumps to the entry after the current one in play order, or stops if there is none. Repeating a single
+    /// track doesn't keep a skip from moving on
+    pub fn skip(&mut self, queue: &mut Queue) {
+        match queue.following(self.current) {
+            Some(next) => self.play(queue, next),
+            None => self.stop(queue),
+        }
+    }
+
+    /// Goes back to the track played before the current one. A few seconds into a track it restarts the track
+    /// instead, like most players do
+    pub fn previous(&mut self, queue: &mut Queue) {
+        if self.is_playing() && self.elapsed() > RESTART_THRESHOLD {
+            let _ = self.seek(Duration::ZERO);
+            return;
+        }
+        if let Some(index) = queue.previous() {
+            self.start(queue, index, false);
+        }
+    }
+
+    pub fn stop(&mut self, queue: &mut Queue) {
+        self.sink.clear();
+        self.generation += 1;
+        self.shared = None;
+        self.preloaded = None;
+        self.set_current(queue, None, true);
+    }
+
+    pub fn toggle_pause(&self) {
+        if self.sink.is_paused() {
+            self.sink.play();
+        } else {
+            self.sink.pause();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.sink.is_paused()
+    }
+
+    /// Whether a track is loaded at all, paused or not
+    pub fn is_playing(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// The volume slider position, 0 to 1
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0., 1.);
+        self.apply_volume();
+    }
+
+    /// Moves the volume slider by `delta`, unmuting if it was muted
+    pub fn change_volume(&mut self, delta: f32) {
+        self.muted = false;
+        self.set_volume(self.volume + delta);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.apply_volume();
+    }
+
+    fn apply_volume(&self) {
+        self.sink.set_volume(if self.muted { 0. } else { slider_to_amplitude(self.volume) });
+    }
+
+    /// How far into the current track playback is
+    pub fn elapsed(&self) -> Duration {
+        let Some(shared) = &self.shared else { return Duration::ZERO };
+        let per_second = shared.sample_rate.load(Ordering::Relaxed) as u64 * shared.channels.load(Ordering::Relaxed) as u64;
+        if per_second == 0 { return Duration::ZERO; }
+        Duration::from_secs_f64(shared.samples.load(Ordering::Relaxed) as f64 / per_second as f64)
+    }
+
+    /// Length of the current track: what the library probed, or else what the decoder can tell
+    pub fn total(&self) -> Option<Duration> {
+        let nanos = self.shared.as_ref()?.total_nanos.load(Ordering::Relaxed);
+        self.duration.or((nanos > 0).then(|| Duration::from_nanos(nanos)))
+    }
+
+    /// Jumps to `pos` in the current track, clamped to its length when that is known
+    pub fn seek(&self, pos: Duration) -> Result<(), SeekError> {
+        if !self.is_playing() { return Ok(()); }
+        let pos = match self.total() {
+            Some(total) => pos.min(total),
+            None => pos,
+        };
+        self.sink.try_seek(pos)
+    }
+
+    /// Moves `seconds` forward (or backward, if negative) in the current track
+    pub fn seek_by(&self, seconds: f64) -> Result<(), SeekError> {
+        let target = (self.elapsed().as_secs_f64() + seconds).max(0.);
+        self.seek(Duration::from_secs_f64(target))
+    }
+
+    /// Jumps to `fraction` (0 to 1) of the current track's length, does nothing if the length isn't known
+    pub fn seek_to_fraction(&self, fraction: f64) -> Result<(), SeekError> {
+        let Some(total) = self.total() else { return Ok(()) };
+        self.seek(total.mul_f64(fraction.clamp(0., 1.)))
+    }
+
+    /// Makes sure the track decoded ahead is still the one that comes next, call after editing the queue. The
+    /// edit may have moved the current track to another index, `queue.current` says where it is now
+    pub fn queue_changed(&mut self, queue: &mut Queue) {
+        self.current = queue.current;
+        self.preload(queue);
+    }
+
+    /// Takes in what happened on the audio thread since the last call, keeping `queue.current` in step with
+    /// what is heard and preloading the track after a new one
+    pub fn poll_events(&mut self, queue: &mut Queue) -> Vec<PlayerEvent> {
+        let mut events = Vec::new();
+        while let Ok((generation, event)) = self.events.try_recv() {
+            // events from a chain that was already replaced by `play`/`stop`
+            if generation != self.generation { continue; }
+            let PlayerEvent::TrackChanged(index) = event;
+            self.preloaded = None;
+            self.set_current(queue, index, true);
+            if index.is_none() { self.shared = None; }
+            self.preload(queue);
+            events.push(event);
+        }
+        events
+    }
+
+    fn set_current(&mut self, queue: &mut Queue, index: Option<usize>, record_history: bool) {
+        if let Some(previous) = self.current.filter(|p| record_history && Some(*p) != index) {
+            queue.push_history(previous);
+        }
+        self.current = index;
+        self.duration = index.and_then(|i| queue.songs.get(i)).and_then(|s| s.duration);
+        queue.current = index;
+    }
+
+    fn preload(&mut self, queue: &mut Queue) {
+        let Some(shared) = &self.shared else { return };
+        let wanted = queue.next_index(self.current).map(|i| (i, queue.songs[i].path.clone()));
+        if wanted.is_some() && wanted == self.preloaded { return; }
+
+        let next = open_from(queue, wanted.map(|(i, _)| i));
+        self.preloaded = next.as_ref().map(|(i, _)| (*i, queue.songs[*i].path.clone()));
+        *shared.next.lock().unwrap() = next;
+    }
+}
+
+/// Maps the volume slider onto an amplitude factor on a decibel scale, so every step of the slider sounds like the
+/// same change in loudness instead of the top half of a linear slider barely making a difference
+fn slider_to_amplitude(slider: f32) -> f32 {
+    if slider <= 0. { return 0.; }
+    10f32.powf((slider - 1.) * VOLUME_RANGE_DB / 20.)
+}
+
+/// Reads a seek target typed by the user: a percentage of `total` ("45%"), a timestamp ("1:23", "1:02:03") or
+/// plain seconds ("90")
+pub fn parse_seek_target(input: &str, total: Option<Duration>) -> Option<Duration> {
+    let input = input.trim();
+    if let Some(percent) = input.strip_suffix('%') {
+        let percent: f64 = percent.trim().parse().ok()?;
+        if !percent.is_finite() { return None; }
+        return Some(total?.mul_f64((percent / 100.).clamp(0., 1.)));
+    }
+    let mut seconds = 0.;
+    for part in input.split(':') {
+        let value: f64 = part.trim().parse().ok()?;
+        // "inf", "nan" and the like parse as floats too
+        if !value.is_finite() || value < 0. { return None; }
+        seconds = seconds * 60. + value;
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+fn total_nanos(source: &TrackSource) -> u64 {
+    source.total_duration().map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Opens the queue entry at `index` for playback, moving on through the queue past entries that fail to open
+fn open_from(queue: &Queue, mut index: Option<usize>) -> Option<(usize, TrackSource)> {
+    // bounded so a queue where nothing opens can't keep us going round in circles
+    for _ in 0..queue.songs.len() {
+        let i = index?;
+        let opened: Result<TrackSource, SongError> = queue.songs.get(i)?.get_source()
+            .map(|s| Box::new(s) as TrackSource);
+        if let Ok(source) = opened { return Some((i, source)); }
+        index = queue.following(Some(i));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A track of fixed samples that, like [`crate::source::SongSource`], says how many are left
+    struct Samples {
+        samples: std::vec::IntoIter<f32>,
+        channels: u16,
+        rate: u32,
+    }
+
+    impl Iterator for Samples {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            self.samples.next()
+        }
+    }
+
+    impl Source for Samples {
+        fn current_frame_len(&self) -> Option<usize> {
+            Some(self.samples.len())
+        }
+
+        fn channels(&self) -> u16 {
+            self.channels
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.rate
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    fn track(channels: u16, rate: u32, samples: Vec<f32>) -> TrackSource {
+        Box::new(Samples { samples: samples.into_iter(), channels, rate })
+    }
+
+    fn chain(first: TrackSource, next: TrackSource) -> (TrackChain, Receiver<(u64, PlayerEvent)>) {
+        let (events, received) = mpsc::channel();
+        let (wake, _) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            next: Mutex::new(Some((1, next))),
+            samples: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(first.sample_rate()),
+            channels: AtomicU32::new(first.channels() as u32),
+            total_nanos: AtomicU64::new(0),
+        });
+        (TrackChain { current: Some((0, first)), shared, generation: 0, events, wake }, received)
+    }
+
+    #[test]
+    fn next_track_format_right_after_the_last_sample() {
+        let (first, next) = (track(2, 44100, vec![0.5; 6]), track(1, 48000, vec![0.25; 3]));
+        let (mut chain, events) = chain(first, next);
+        assert_eq!((chain.channels(), chain.sample_rate(), chain.current_frame_len()), (2, 44100, Some(6)));
+
+        assert_eq!(chain.by_ref().take(6).collect::<Vec<f32>>(), [0.5; 6]);
+        // where the output starts its next frame
+        assert_eq!((chain.channels(), chain.sample_rate(), chain.current_frame_len()), (1, 48000, Some(3)));
+        assert_eq!(events.try_recv().ok(), Some((0, PlayerEvent::TrackChanged(Some(1)))));
+        assert_eq!(chain.shared.samples.load(Ordering::Relaxed), 0);
+
+        assert_eq!(chain.by_ref().collect::<Vec<f32>>(), [0.25; 3]);
+        assert_eq!(chain.current_frame_len(), Some(0));
+        assert_eq!(events.try_recv().ok(), Some((0, PlayerEvent::TrackChanged(None))));
+    }
+
+    #[test]
+    fn the_output_converts_each_track_by_its_own_format() {
+        let (first, next) = (track(1, 44100, vec![1.; 3]), track(2, 44100, vec![0.5, -0.5, 0.5, -0.5]));
+        let (chain, _events) = chain(first, next);
+        // mono duplicated to both channels, then the stereo track as it is
+        let mut out: Vec<f32> = Vec::new();
+        // not collected, rodio's size hints overflow partway through a frame
+        for sample in rodio::source::UniformSourceIterator::<TrackChain, f32>::new(chain, 2, 44100) { out.push(sample); }
+        assert_eq!(out, [1., 1., 1., 1., 1., 1., 0.5, -0.5, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn seek_targets() {
+        let total = Some(Duration::from_secs(200));
+        assert_eq!(parse_seek_target("90", total), Some(Duration::from_secs(90)));
+        assert_eq!(parse_seek_target("1:23", total), Some(Duration::from_secs(83)));
+        assert_eq!(parse_seek_target(" 1:02:03 ", total), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_seek_target("45%", total), Some(Duration::from_secs(90)));
+        assert_eq!(parse_seek_target("150%", total), total);
+        assert_eq!(parse_seek_target("45%", None), None);
+    }
+
+    #[test]
+    fn bad_seek_targets() {
+        let total = Some(Duration::from_secs(200));
+        for input in ["-5", "1:-5", "nan", "inf", "1e30", "nan%", "inf%", "", "1:xx"] {
+            assert_eq!(parse_seek_target(input, total), None, "{:?}", input);
+        }
+    }
+}
@@ -1,72 +1,204 @@
-use std::{fs::{self, File}, io::BufReader};
+use std::{fmt, fs, io, ops::RangeInclusive, path::Path, time::Duration};
+use glob::Pattern;
 // use color_eyre::owo_colors::OwoColorize;
 use ratatui::{style::Stylize, text::Text, widgets::ListState};
-use rodio::Decoder;
-use symphonia::core::{formats::FormatOptions, io::{MediaSourceStream, MediaSourceStreamOptions}, meta::{Limit, MetadataOptions}};
-use symphonia::default::get_probe;
+use symphonia::core::meta::{StandardTagKey, Tag};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
 
+use crate::{formats::{audio_track, chunk_tags, codecs, is_audio, probe, track_duration}, index::{FileStamp, LibraryIndex}, source::SongSource};
 
+/// How many queue edits can be undone
+const UNDO_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize)]
 pub struct Song {
     pub path: String,
 	pub title: String,
-	pub artist: String,
-    pub album: String,
-    pub track_num: String,
-    pub album_tracks_total: String,
-    pub year: String
+	pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub track_num: Option<u32>,
+    pub album_tracks_total: Option<u32>,
+    pub disc_num: Option<u32>,
+    pub disc_total: Option<u32>,
+    /// part of a compilation, filed under "Various Artists" unless it names an album artist
+    #[serde(default)]
+    pub compilation: bool,
+    pub year: Option<i32>,
+    /// length of the audio, as far as the container tells
+    pub duration: Option<Duration>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    /// average bitrate in kbit/s, from the file size and the duration
+    pub bitrate: Option<u32>,
+    /// short name of the codec, "mp3", "flac", ...
+    pub codec: Option<String>,
+}
+
+/// Why a file could not be turned into a [`Song`]
+#[derive(Debug)]
+pub enum SongError {
+    /// the file (or the directory containing it) could not be read
+    Io(io::Error),
+    /// symphonia has no demuxer or decoder for the file, or it is damaged beyond probing
+    UnsupportedFormat(String),
+    /// the file was recognised but holds no audio track to take any properties from
+    NoMetadata,
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SongError::Io(err) => write!(f, "I/O error: {}", err),
+            SongError::UnsupportedFormat(reason) => write!(f, "unsupported format: {}", reason),
+            SongError::NoMetadata => write!(f, "no audio track found"),
+        }
+    }
+}
+
+impl std::error::Error for SongError {}
+
+impl Clone for SongError {
+    fn clone(&self) -> Self {
+        match self {
+            SongError::Io(err) => SongError::Io(io::Error::new(err.kind(), err.to_string())),
+            SongError::UnsupportedFormat(reason) => SongError::UnsupportedFormat(reason.clone()),
+            SongError::NoMetadata => SongError::NoMetadata,
+        }
+    }
+}
+
+impl From<io::Error> for SongError {
+    fn from(err: io::Error) -> Self {
+        SongError::Io(err)
+    }
+}
+
+impl From<symphonia::core::errors::Error> for SongError {
+    fn from(err: symphonia::core::errors::Error) -> Self {
+        match err {
+            symphonia::core::errors::Error::IoError(err) => SongError::Io(err),
+            other => SongError::UnsupportedFormat(other.to_string()),
+        }
+    }
 }
 
 impl Song {
-    pub fn new(path: String) -> Self {
-        let file: File = File::open(&path).expect(&format!("Failed to open file {}", path.clone()));
-        let probe = get_probe();
-        let mut format = probe.format(
-            &Default::default(),
-            MediaSourceStream::new(
-                Box::new(file),
-                MediaSourceStreamOptions { buffer_len: (1024*512) }
-            ),
-            &FormatOptions { prebuild_seek_index: true, seek_index_fill_rate: 128, enable_gapless: true },
-            &MetadataOptions {
-                limit_metadata_bytes: Limit::None,
-                limit_visual_bytes: Limit::None
-            }
-        ).expect("Failed to probe format");
-        
+    pub fn new(path: String) -> Result<Self, SongError> {
+        let file_size = fs::metadata(&path)?.len();
+        let mut probed = probe(Path::new(&path))?;
+        let Some(params) = audio_track(probed.format.as_ref()).map(|t| t.codec_params.clone()) else {
+            return Err(SongError::NoMetadata);
+        };
+        let duration = track_duration(&params);
+        let bitrate = duration
+            .filter(|d| !d.is_zero())
+            .map(|d| (file_size as f64 * 8. / d.as_secs_f64() / 1000.).round() as u32);
 
-        let dta = format.metadata.get().unwrap();        
-        let tags = dta.current().unwrap().tags();
-        // println!();
-        Song {
-            path: path.clone(),
-            title: tags[0].value.to_string(),
-            artist: tags[1].value.to_string(),
-            album: tags[4].value.to_string(),
-            track_num: tags[2].value.to_string(),
-            album_tracks_total: tags[3].value.to_string(),
-            year: (tags[5].value.to_string()),
-            // "TITLE:{}, ARTIST:{}, ALBUM:{}, TRACK_NUM:{}, TRACK_TOTAL:{}, YEAR:{}", tags[0].value, tags[1].value, tags[4].value, tags[2].value, tags[3].value, tags[5].value
-
-            // source: std::fs::File::open(&path).expect("failed to open media"),
-            // stream: MediaSourceStream::new(Box::new(std::fs::File::open(&path).expect("failed to open media")), Default::default()),
-            // source: Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap()
+        // tags read ahead of the container (ID3v2 on mp3) end up in the probe result, tags that are part of
+        // the container itself (MP4 atoms, Vorbis comments) have to be asked from the format reader
+        let mut tags: Vec<Tag> = Vec::new();
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            tags.extend_from_slice(revision.tags());
         }
+        if let Some(revision) = probed.format.metadata().current() {
+            tags.extend_from_slice(revision.tags());
+        }
+        // and WAV/AIFF keep the tags worth having in chunks symphonia skips
+        tags.extend(chunk_tags(Path::new(&path))?);
+
+        let (track_num, track_total) = parse_count(
+            find_tag(&tags, StandardTagKey::TrackNumber, &["TRCK", "TRK", "TRACKNUMBER", "trkn"]).as_deref(),
+        );
+        let album_tracks_total = parse_number(
+            find_tag(&tags, StandardTagKey::TrackTotal, &["TRACKTOTAL", "TOTALTRACKS", "TXXX:TRACKTOTAL"]).as_deref(),
+        ).or(track_total);
+        let (disc_num, disc_total) = parse_count(
+            find_tag(&tags, StandardTagKey::DiscNumber, &["TPOS", "TPA", "DISCNUMBER", "disk"]).as_deref(),
+        );
+        let disc_total = parse_number(
+            find_tag(&tags, StandardTagKey::DiscTotal, &["DISCTOTAL", "TOTALDISCS", "TXXX:DISCTOTAL"]).as_deref(),
+        ).or(disc_total);
+        let compilation = find_tag(&tags, StandardTagKey::Compilation, &["TCMP", "TCP", "COMPILATION", "cpil"])
+            .is_some_and(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"));
+        let year = parse_year(
+            find_tag(&tags, StandardTagKey::Date, &["TDRC", "TYER", "TYE", "DATE", "YEAR", "\u{a9}day"]).as_deref(),
+        );
+
+        Ok(Song {
+            title: find_tag(&tags, StandardTagKey::TrackTitle, &["TIT2", "TT2", "TITLE", "\u{a9}nam"])
+                .unwrap_or_else(|| file_stem(&path)),
+            artist: find_tag(&tags, StandardTagKey::Artist, &["TPE1", "TP1", "ARTIST", "\u{a9}ART"]),
+            album_artist: find_tag(&tags, StandardTagKey::AlbumArtist, &["TPE2", "TP2", "ALBUMARTIST", "ALBUM ARTIST", "aART"]),
+            album: find_tag(&tags, StandardTagKey::Album, &["TALB", "TAL", "ALBUM", "\u{a9}alb"]),
+            genre: find_tag(&tags, StandardTagKey::Genre, &["TCON", "TCO", "GENRE", "\u{a9}gen"]),
+            track_num,
+            album_tracks_total,
+            disc_num,
+            disc_total,
+            compilation,
+            year,
+            duration,
+            sample_rate: params.sample_rate,
+            channels: params.channels.map(|c| c.count() as u16),
+            bitrate,
+            codec: codecs().get_codec(params.codec).map(|c| c.short_name.to_owned()),
+            path,
+        })
 	}
-    pub fn get_source(&self) -> rodio::Decoder<std::io::BufReader<File>>{
-        return Decoder::new(BufReader::new(File::open(self.path.clone()).unwrap())).unwrap()
+    pub fn get_source(&self) -> Result<SongSource, SongError> {
+        SongSource::open(Path::new(&self.path))
+    }
+}
+
+#[cfg(test)]
+impl Song {
+    /// A song with nothing but a path, the title taken from it, for tests to fill in what they need
+    pub fn untagged(path: &str) -> Self {
+        Song {
+            path: path.to_owned(),
+            title: file_stem(path),
+            artist: None,
+            album_artist: None,
+            album: None,
+            genre: None,
+            track_num: None,
+            album_tracks_total: None,
+            disc_num: None,
+            disc_total: None,
+            compilation: false,
+            year: None,
+            duration: None,
+            sample_rate: None,
+            channels: None,
+            bitrate: None,
+            codec: None,
+        }
     }
 }
 
 impl Clone for Song {
     fn clone(&self) -> Self {
-        return Song {
+        Song {
             path: self.path.clone(),
             title:self.title.clone(),
             artist:self.artist.clone(),
+            album_artist: self.album_artist.clone(),
             album:self.album.clone(),
-            track_num:self.track_num.clone(),
-            album_tracks_total:self.album_tracks_total.clone(),
-            year: self.year.clone(),
+            genre: self.genre.clone(),
+            track_num:self.track_num,
+            album_tracks_total:self.album_tracks_total,
+            disc_num: self.disc_num,
+            disc_total: self.disc_total,
+            compilation: self.compilation,
+            year: self.year,
+            duration: self.duration,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            bitrate: self.bitrate,
+            codec: self.codec.clone(),
             // source: self.source.try_clone().unwrap(),
             // stream: self.stream
         }
@@ -85,22 +217,83 @@ impl From<Song> for Text<'_> {
         let mut txt = ratatui::text::Text::raw("");
         txt.push_span(format!("{}\n", value.title.clone()).bold());
         txt.push_span(" - ");
-        txt.push_span(format!("{}", value.artist.clone()));
-        return txt
+        txt.push_span(value.artist.unwrap_or_else(|| "Unknown Artist".to_owned()));
+        txt
     }
 }
 impl From<Song> for String {
     fn from(value: Song) -> Self {
-        return value.title
+        value.title
+    }
+}
+/// What happens when playback reaches the end of the queue, or of a track
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum RepeatMode {
+    /// stop after the last track
+    #[default]
+    Off,
+    /// start over from the first track
+    All,
+    /// play the current track again and again
+    One,
+}
+
+impl RepeatMode {
+    /// Off -> All -> One -> Off
+    pub fn cycle(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
     }
 }
+
+/// Play order of a shuffled queue. The visible queue keeps its order, only the order tracks are played in changes
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Shuffle {
+    /// same seed and same queue give the same order
+    pub seed: u64,
+    /// indices into `Queue::songs` in the order they are played
+    pub order: Vec<usize>,
+}
+
 pub struct Queue  {
     pub songs: Vec<Song>,
-    pub state: ListState
+    pub state: ListState,
+    /// index into `songs` of the track last sent to the audio output
+    pub current: Option<usize>,
+    pub repeat: RepeatMode,
+    /// the shuffled play order, `None` while playing in queue order
+    pub shuffle: Option<Shuffle>,
+    /// indices of the tracks played before the current one, most recent last
+    pub history: Vec<usize>,
+    /// other end of a range selection, the selected entry being the end that moves
+    pub mark: Option<usize>,
+    /// entries cut or copied, for pasting
+    pub clipboard: Vec<Song>,
+    /// the queue as it was before each edit, most recent last
+    undo: Vec<QueueSnapshot>,
+}
+
+/// Everything an edit can change, kept for [`Queue::undo`]
+struct QueueSnapshot {
+    songs: Vec<Song>,
+    current: Option<usize>,
+    history: Vec<usize>,
+    shuffle: Option<Shuffle>,
+    selected: Option<usize>,
+}
+
+/// Where an entry of an edited queue comes from, see [`Queue::rearrange`]
+enum Slot {
+    /// the entry that was at this index before
+    Kept(usize),
+    Added(Box<Song>),
 }
 
 impl Queue {
-    pub fn new<T>() -> Self {
+    pub fn new() -> Self {
         // let mut items: Vec<ListItem> = Vec::new();
         // for s in songs { // create list of songs displayed in the queue tab
         //     let mut txt = ratatui::text::Text::raw("");
@@ -114,23 +307,674 @@ impl Queue {
 
         Queue {
             songs: Vec::new(),
-            state: ListState::default()
+            state: ListState::default(),
+            current: None,
+            repeat: RepeatMode::Off,
+            shuffle: None,
+            history: Vec::new(),
+            mark: None,
+            clipboard: Vec::new(),
+            undo: Vec::new(),
+        }
+    }
+
+    /// The entry that plays once the one at `current` has finished on its own. Unlike [`Queue::following`] this
+    /// honours [`RepeatMode::One`]
+    pub fn next_index(&self, current: Option<usize>) -> Option<usize> {
+        match (self.repeat, current) {
+            (RepeatMode::One, Some(c)) if c < self.songs.len() => Some(c),
+            _ => self.following(current),
+        }
+    }
+
+    /// The entry after `current` in play order (queue order or shuffled), wrapping around with
+    /// [`RepeatMode::All`] and `None` at the end of the queue otherwise
+    pub fn following(&self, current: Option<usize>) -> Option<usize> {
+        let order: Vec<usize> = match &self.shuffle {
+            Some(shuffle) => shuffle.order.clone(),
+            None => (0..self.songs.len()).collect(),
+        };
+        let next = match current.and_then(|c| order.iter().position(|i| *i == c)) {
+            Some(position) => position + 1,
+            None => 0,
+        };
+        match order.get(next) {
+            Some(index) => Some(*index),
+            None if self.repeat != RepeatMode::Off => order.first().copied(),
+            None => None,
+        }
+    }
+
+    /// Remembers that playback moved on from `index`, for [`Queue::previous`]
+    pub fn push_history(&mut self, index: usize) {
+        self.history.push(index);
+    }
+
+    /// The track played before the current one, taking it off the history
+    pub fn previous(&mut self) -> Option<usize> {
+        self.history.pop().filter(|i| *i < self.songs.len())
+    }
+
+    /// Turns shuffling on with the given seed, or off again. Turning it off just carries on in queue order from
+    /// the track that is playing
+    pub fn set_shuffle(&mut self, seed: Option<u64>) {
+        self.shuffle = seed.map(|seed| Shuffle { seed, order: Vec::new() });
+        self.reshuffle(&[]);
+    }
+
+    /// Adds `songs` to the end of the queue
+    pub fn append(&mut self, songs: Vec<Song>) {
+        self.save_undo();
+        self.songs.extend(songs);
+        self.reshuffle(&[]);
+    }
+
+    /// Adds `songs` right after the current track, or at the front if nothing has been played yet. When
+    /// shuffling they are played next as well
+    pub fn insert_next(&mut self, songs: Vec<Song>) {
+        self.save_undo();
+        let at = self.current.map_or(0, |c| c + 1);
+        let count = songs.len();
+        self.songs.splice(at..at, songs);
+        self.remap_indices(|i| Some(if i >= at { i + count } else { i }));
+        self.reshuffle(&(at..at + count).collect::<Vec<usize>>());
+    }
+
+    /// Throws the current queue away and puts `songs` in its place
+    pub fn replace(&mut self, songs: Vec<Song>) {
+        self.save_undo();
+        self.songs = songs;
+        self.mark = None;
+        self.current = None;
+        self.history.clear();
+        self.state.select(if self.songs.is_empty() { None } else { Some(0) });
+        self.reshuffle(&[]);
+    }
+
+    /// Points the current track, the selection, the history and the shuffle order at the entries' new indices
+    /// after the queue was edited. `map` gives `None` for entries that are gone
+    fn remap_indices(&mut self, map: impl Fn(usize) -> Option<usize>) {
+        self.current = self.current.and_then(&map);
+        self.history = self.history.iter().filter_map(|i| map(*i)).collect();
+        if let Some(shuffle) = self.shuffle.as_mut() {
+            shuffle.order = shuffle.order.iter().filter_map(|i| map(*i)).collect();
+        }
+        if let Some(selected) = self.state.selected() {
+            self.state.select(map(selected));
+        }
+        self.mark = self.mark.and_then(&map);
+    }
+
+    /// The selected entries: from the mark to the selected entry, or just the selected one without a mark
+    pub fn selected_range(&self) -> Option<RangeInclusive<usize>> {
+        let selected = self.state.selected().filter(|s| *s < self.songs.len())?;
+        let mark = self.mark.filter(|m| *m < self.songs.len()).unwrap_or(selected);
+        Some(selected.min(mark)..=selected.max(mark))
+    }
+
+    /// Starts a range selection at the selected entry, or drops the one in progress
+    pub fn toggle_mark(&mut self) {
+        self.mark = match self.mark {
+            Some(_) => None,
+            None => self.state.selected(),
+        };
+    }
+
+    /// Takes the selected entries out of the queue and returns them. The entry after them gets selected
+    pub fn remove_selected(&mut self) -> Vec<Song> {
+        let Some(range) = self.selected_range() else { return Vec::new() };
+        self.save_undo();
+        let removed = self.songs[range.clone()].to_vec();
+        let layout = (0..self.songs.len()).filter(|i| !range.contains(i)).map(Slot::Kept).collect();
+        self.rearrange(layout);
+        self.mark = None;
+        self.state.select(if self.songs.is_empty() { None } else { Some((*range.start()).min(self.songs.len() - 1)) });
+        removed
+    }
+
+    /// Moves the selected entries to the clipboard
+    pub fn cut_selected(&mut self) {
+        self.clipboard = self.remove_selected();
+    }
+
+    pub fn copy_selected(&mut self) {
+        if let Some(range) = self.selected_range() {
+            self.clipboard = self.songs[range].to_vec();
+            self.mark = None;
+        }
+    }
+
+    /// Puts the clipboard in after the selected entry, or at the end without a selection, and selects what was
+    /// pasted
+    pub fn paste(&mut self) {
+        if self.clipboard.is_empty() { return; }
+        self.save_undo();
+        let at = self.selected_range().map_or(self.songs.len(), |r| r.end() + 1);
+        let layout = (0..at).map(Slot::Kept)
+            .chain(self.clipboard.iter().cloned().map(|s| Slot::Added(Box::new(s))))
+            .chain((at..self.songs.len()).map(Slot::Kept))
+            .collect();
+        let added = self.rearrange(layout);
+        self.reshuffle(&[]);
+        self.mark = added.first().copied();
+        self.state.select(added.last().copied());
+    }
+
+    /// Moves the selected entries one place up (`offset` -1) or down (1), they stay selected
+    pub fn move_selected(&mut self, offset: isize) {
+        let Some(range) = self.selected_range() else { return };
+        let (start, end) = (*range.start(), *range.end());
+        let mut layout: Vec<usize> = (0..self.songs.len()).collect();
+        if offset < 0 && start > 0 {
+            layout[start - 1..=end].rotate_left(1);
+        } else if offset > 0 && end + 1 < self.songs.len() {
+            layout[start..=end + 1].rotate_right(1);
+        } else {
+            return;
+        }
+        self.save_undo();
+        self.rearrange(layout.into_iter().map(Slot::Kept).collect());
+    }
+
+    /// Moves the selected entries right behind the current track so they play next, shuffled or not
+    pub fn play_selected_next(&mut self) {
+        let Some(range) = self.selected_range() else { return };
+        if self.current.is_some_and(|c| range.contains(&c)) { return; }
+        self.save_undo();
+        let at = self.current.map_or(0, |c| c + 1);
+        let rest = |i: &usize| !range.contains(i);
+        let layout = (0..at).filter(rest)
+            .chain(range.clone())
+            .chain((at..self.songs.len()).filter(rest))
+            .map(Slot::Kept)
+            .collect();
+        self.rearrange(layout);
+        let moved = self.selected_range().map_or(Vec::new(), |r| r.collect());
+        self.reshuffle(&moved);
+    }
+
+    /// Empties the queue, the emptied queue can still be brought back with [`Queue::undo`]
+    pub fn clear(&mut self) {
+        self.replace(Vec::new());
+    }
+
+    /// Puts the queue back the way it was before the last edit. Returns whether there was anything to undo.
+    /// The track being heard stays current if it's still in the restored queue
+    pub fn undo(&mut self) -> bool {
+        let Some(snapshot) = self.undo.pop() else { return false };
+        let playing = self.current.map(|c| self.songs[c].path.clone());
+        let path_at = |i: usize| snapshot.songs.get(i).map(|s| &s.path);
+        self.current = match &playing {
+            Some(path) if snapshot.current.and_then(path_at) == Some(path) => snapshot.current,
+            Some(path) => snapshot.songs.iter().position(|s| &s.path == path),
+            None => None,
+        };
+        self.songs = snapshot.songs;
+        self.history = snapshot.history;
+        self.shuffle = snapshot.shuffle;
+        self.state.select(snapshot.selected);
+        self.mark = None;
+        self.reshuffle(&[]);
+        true
+    }
+
+    fn save_undo(&mut self) {
+        if self.undo.len() == UNDO_LIMIT { self.undo.remove(0); }
+        self.undo.push(QueueSnapshot {
+            songs: self.songs.clone(),
+            current: self.current,
+            history: self.history.clone(),
+            shuffle: self.shuffle.clone(),
+            selected: self.state.selected(),
+        });
+    }
+
+    /// Rebuilds `songs` from `layout` and points everything holding an index at the entries' new places. Returns
+    /// where the added entries ended up
+    fn rearrange(&mut self, layout: Vec<Slot>) -> Vec<usize> {
+        let mut old: Vec<Option<Song>> = std::mem::take(&mut self.songs).into_iter().map(Some).collect();
+        let mut new_index: Vec<Option<usize>> = vec![None; old.len()];
+        let mut added = Vec::new();
+        for (position, slot) in layout.into_iter().enumerate() {
+            match slot {
+                Slot::Kept(i) => {
+                    new_index[i] = Some(position);
+                    self.songs.extend(old[i].take());
+                }
+                Slot::Added(song) => {
+                    added.push(position);
+                    self.songs.push(*song);
+                }
+            }
         }
+        self.remap_indices(|i| new_index.get(i).copied().flatten());
+        added
     }
 
-    pub fn push_from_path(&mut self, path: &String) {
-        self.songs.push(Song::new(path.clone()));
+    /// Rebuilds the shuffled play order after the queue changed or playback jumped somewhere by hand: what was
+    /// already played (the history and the current track) keeps its place, `first_up` comes right after it and
+    /// everything else is shuffled behind them
+    pub fn reshuffle(&mut self, first_up: &[usize]) {
+        let Some(shuffle) = self.shuffle.as_mut() else { return };
+        let mut played: Vec<usize> = Vec::new();
+        for i in self.history.iter().copied().chain(self.current) {
+            // a track played more than once counts where it was played last
+            played.retain(|p| *p != i);
+            played.push(i);
+        }
+        let mut rest: Vec<usize> = (0..self.songs.len())
+            .filter(|i| !played.contains(i) && !first_up.contains(i))
+            .collect();
+        // mixing in the length keeps orders for different queues apart while staying reproducible
+        let mut rng = SplitMix64(shuffle.seed ^ (self.songs.len() as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        for i in (1..rest.len()).rev() {
+            rest.swap(i, (rng.next() % (i as u64 + 1)) as usize);
+        }
+        shuffle.order = played.into_iter().chain(first_up.iter().copied()).chain(rest).collect();
     }
 
     // pub fn go_to() {
         
     // }
 }
-pub fn dir_to_songs(dir_path: &String) -> Vec<Song> {
-    let paths: fs::ReadDir = fs::read_dir(dir_path).unwrap();
+
+/// Small seedable generator for the shuffle order, <https://prng.di.unimi.it/splitmix64.c>
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Looks a tag up by its standard key first and falls back to the raw frame/atom/comment names, since not every
+/// format gets its keys mapped by symphonia
+fn find_tag(tags: &[Tag], std_key: StandardTagKey, raw_keys: &[&str]) -> Option<String> {
+    tags.iter()
+        .find(|t| t.std_key == Some(std_key))
+        .or_else(|| tags.iter().find(|t| raw_keys.iter().any(|k| t.key.eq_ignore_ascii_case(k))))
+        .map(|t| t.value.to_string().trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+/// A tag that isn't a number (track "A1" on a vinyl rip, ...) is left out rather than costing the whole song
+fn parse_number(value: Option<&str>) -> Option<u32> {
+    value.and_then(|v| v.trim().parse().ok())
+}
+
+/// Reads "3" or "3/12" style numbers, which carry the total (of tracks on the album, discs in the set) as well
+fn parse_count(value: Option<&str>) -> (Option<u32>, Option<u32>) {
+    let Some((number, total)) = value.map(|v| v.split_once('/').unwrap_or((v, ""))) else { return (None, None) };
+    (parse_number(Some(number)), parse_number(Some(total)))
+}
+
+/// Takes the year out of dates like "2019", "2019-05-01" or "2019-05-01T00:00:00". Anything not starting with
+/// exactly four digits isn't a year we can trust
+fn parse_year(value: Option<&str>) -> Option<i32> {
+    let year = value?.trim().split(|c: char| !c.is_ascii_digit()).next()?;
+    if year.len() != 4 { return None; }
+    year.parse().ok()
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+/// A file that was left out of the library while scanning, and why
+#[derive(Clone)]
+pub struct SkippedFile {
+    pub path: String,
+    pub error: SongError,
+}
+
+/// Walks `root` recursively and reads every file into a [`Song`], collecting the ones that couldn't be read instead
+/// of giving up. `include`/`exclude` are matched against the path relative to `root`; symlinks are followed, loops
+/// among them are reported as skipped rather than walked forever. Files already in `index` unchanged aren't probed.
+/// `on_file` is called for every file read, to report progress
+pub fn dir_to_songs(
+    root: &Path,
+    include: &[Pattern],
+    exclude: &[Pattern],
+    index: &mut LibraryIndex,
+    on_file: &mut dyn FnMut()
+) -> (Vec<Song>, Vec<SkippedFile>) {
     let mut songs: Vec<Song> = Vec::new();
-    for p in paths {
-        songs.push(Song::new(p.unwrap().path().display().to_string()));
+    let mut skipped: Vec<SkippedFile> = Vec::new();
+    let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_path_buf();
+
+    let walker = WalkDir::new(root)
+        .follow_links(true)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !exclude.iter().any(|p| p.matches_path(&relative(e.path()))));
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let path = err.path().unwrap_or(root).display().to_string();
+                skipped.push(SkippedFile { path, error: SongError::Io(err.into()) });
+                continue;
+            }
+        };
+        // cover images, playlists and notes kept among the music are neither songs nor broken songs
+        if !entry.file_type().is_file() || !is_audio(entry.path()) { continue; }
+        if !include.is_empty() && !include.iter().any(|p| p.matches_path(&relative(entry.path()))) { continue; }
+
+        on_file();
+        let path = entry.path().display().to_string();
+        let stamp = match entry.metadata() {
+            Ok(metadata) => FileStamp::of(&metadata),
+            Err(err) => {
+                skipped.push(SkippedFile { path, error: SongError::Io(err.into()) });
+                continue;
+            }
+        };
+        match index.get_or_probe(&path, stamp) {
+            Ok(song) => songs.push(song),
+            Err(error) => skipped.push(SkippedFile { path, error }),
+        }
+    }
+    (songs, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(path: &str) -> Song {
+        Song::untagged(path)
+    }
+
+    fn tag(std_key: Option<StandardTagKey>, key: &str, value: &str) -> Tag {
+        Tag::new(std_key, key, symphonia::core::meta::Value::from(value))
+    }
+
+    #[test]
+    fn standard_keys_win() {
+        let tags = [
+            tag(None, "TIT2", "Raw"),
+            tag(Some(StandardTagKey::TrackTitle), "TITLE", " Standard "),
+        ];
+        assert_eq!(find_tag(&tags, StandardTagKey::TrackTitle, &["TIT2"]).as_deref(), Some("Standard"));
+        // raw keys as a fallback, matched ignoring case
+        let tags = [tag(None, "tracknumber", "3"), tag(None, "ALBUM", "  ")];
+        assert_eq!(find_tag(&tags, StandardTagKey::TrackNumber, &["TRCK", "TRACKNUMBER"]).as_deref(), Some("3"));
+        // blank values count as missing
+        assert_eq!(find_tag(&tags, StandardTagKey::Album, &["ALBUM"]), None);
+        assert_eq!(find_tag(&tags, StandardTagKey::Genre, &["GENRE"]), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number(Some(" 07 ")), Some(7));
+        assert_eq!(parse_number(Some("12")), Some(12));
+        for bad in ["", " ", "-3", "A1", "99999999999", "1.5"] {
+            assert_eq!(parse_number(Some(bad)), None, "{:?}", bad);
+        }
+        assert_eq!(parse_number(None), None);
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(parse_count(Some("3/12")), (Some(3), Some(12)));
+        assert_eq!(parse_count(Some(" 07 ")), (Some(7), None));
+        assert_eq!(parse_count(Some("3/")), (Some(3), None));
+        assert_eq!(parse_count(Some("/12")), (None, Some(12)));
+        assert_eq!(parse_count(Some(" 3 / 12 ")), (Some(3), Some(12)));
+        // one half being off doesn't cost the other
+        assert_eq!(parse_count(Some("A1/12")), (None, Some(12)));
+        assert_eq!(parse_count(Some("3/99999999999")), (Some(3), None));
+        assert_eq!(parse_count(Some("-1/-2")), (None, None));
+        assert_eq!(parse_count(Some("")), (None, None));
+        assert_eq!(parse_count(None), (None, None));
+    }
+
+    #[test]
+    fn years() {
+        assert_eq!(parse_year(Some("1999")), Some(1999));
+        assert_eq!(parse_year(Some(" 1999-05-01 ")), Some(1999));
+        assert_eq!(parse_year(Some("2019-05-01T00:00:00")), Some(2019));
+        assert_eq!(parse_year(Some("2003-ish")), Some(2003));
+        for bad in ["", "99", "-500", "-1999", "99999999999", "19999", "ca. 1999", "May 1999"] {
+            assert_eq!(parse_year(Some(bad)), None, "{:?}", bad);
+        }
+        assert_eq!(parse_year(None), None);
+    }
+
+    fn queue(len: usize) -> Queue {
+        let mut queue = Queue::new();
+        queue.append((0..len).map(|i| song(&i.to_string())).collect());
+        queue
+    }
+
+    /// Every entry `following` visits from `start` until it stops or comes back round, capped at `limit`
+    fn play_order(queue: &Queue, start: Option<usize>, limit: usize) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut current = queue.following(start);
+        while let Some(i) = current {
+            if order.len() == limit { break; }
+            order.push(i);
+            current = queue.following(Some(i));
+        }
+        order
     }
-    return songs;
-}
\ No newline at end of file
+
+    #[test]
+    fn following_in_queue_order() {
+        let mut queue = queue(3);
+        assert_eq!(play_order(&queue, None, 10), [0, 1, 2]);
+        assert_eq!(queue.following(Some(2)), None);
+
+        queue.repeat = RepeatMode::All;
+        assert_eq!(queue.following(Some(2)), Some(0));
+        assert_eq!(queue.next_index(Some(1)), Some(2));
+
+        queue.repeat = RepeatMode::One;
+        assert_eq!(queue.next_index(Some(1)), Some(1));
+        // skipping by hand still moves on, and wraps like All
+        assert_eq!(queue.following(Some(1)), Some(2));
+        assert_eq!(queue.following(Some(2)), Some(0));
+        assert_eq!(queue.next_index(None), Some(0));
+    }
+
+    #[test]
+    fn following_an_empty_queue() {
+        let mut queue = queue(0);
+        for repeat in [RepeatMode::Off, RepeatMode::All, RepeatMode::One] {
+            queue.repeat = repeat;
+            assert_eq!(queue.following(None), None);
+            assert_eq!(queue.next_index(Some(0)), None);
+        }
+    }
+
+    #[test]
+    fn shuffle_plays_everything_once() {
+        let mut queue = queue(20);
+        queue.set_shuffle(Some(7));
+        let mut order = play_order(&queue, None, 100);
+        assert_ne!(order, (0..20).collect::<Vec<usize>>());
+        order.sort();
+        assert_eq!(order, (0..20).collect::<Vec<usize>>());
+
+        let last = queue.shuffle.as_ref().unwrap().order[19];
+        assert_eq!(queue.following(Some(last)), None);
+        queue.repeat = RepeatMode::All;
+        assert_eq!(queue.following(Some(last)), queue.shuffle.as_ref().map(|s| s.order[0]));
+    }
+
+    #[test]
+    fn shuffle_is_reproducible() {
+        let order = |seed| {
+            let mut queue = queue(20);
+            queue.set_shuffle(Some(seed));
+            queue.shuffle.unwrap().order
+        };
+        assert_eq!(order(1), order(1));
+        assert_ne!(order(1), order(2));
+    }
+
+    #[test]
+    fn reshuffle_keeps_what_was_played() {
+        let mut queue = queue(10);
+        queue.set_shuffle(Some(3));
+        queue.history = vec![4, 7, 4];
+        queue.current = Some(2);
+        queue.reshuffle(&[9, 0]);
+        let order = &queue.shuffle.as_ref().unwrap().order;
+        // a track played twice counts where it was played last
+        assert_eq!(order[..5], [7, 4, 2, 9, 0]);
+        assert_eq!(order.len(), 10);
+        assert_eq!(queue.following(Some(2)), Some(9));
+    }
+
+    #[test]
+    fn insert_next_plays_next_when_shuffled() {
+        let mut queue = queue(10);
+        queue.set_shuffle(Some(5));
+        queue.current = Some(3);
+        queue.insert_next(vec![song("a"), song("b")]);
+        assert_eq!(queue.songs[4].path, "a");
+        assert_eq!(queue.following(Some(3)), Some(4));
+        assert_eq!(queue.following(Some(4)), Some(5));
+    }
+
+    #[test]
+    fn cycle_repeat() {
+        assert_eq!(RepeatMode::Off.cycle(), RepeatMode::All);
+        assert_eq!(RepeatMode::All.cycle(), RepeatMode::One);
+        assert_eq!(RepeatMode::One.cycle(), RepeatMode::Off);
+    }
+
+    fn paths(queue: &Queue) -> Vec<&str> {
+        queue.songs.iter().map(|s| s.path.as_str()).collect()
+    }
+
+    #[test]
+    fn rearrange_follows_the_entries() {
+        let mut queue = queue(4);
+        queue.current = Some(1);
+        queue.history = vec![0, 3];
+        queue.state.select(Some(2));
+        let added = queue.rearrange(vec![Slot::Kept(3), Slot::Added(Box::new(song("x"))), Slot::Kept(1), Slot::Kept(2)]);
+        assert_eq!(added, [1]);
+        assert_eq!(paths(&queue), ["3", "x", "1", "2"]);
+        assert_eq!(queue.current, Some(2));
+        // entry 0 is gone, from the history as well
+        assert_eq!(queue.history, [0]);
+        assert_eq!(queue.state.selected(), Some(3));
+    }
+
+    #[test]
+    fn remove_selected_range() {
+        let mut queue = queue(5);
+        queue.current = Some(4);
+        queue.state.select(Some(1));
+        queue.toggle_mark();
+        queue.state.select(Some(3));
+        let removed = queue.remove_selected();
+        assert_eq!(removed.iter().map(|s| s.path.as_str()).collect::<Vec<&str>>(), ["1", "2", "3"]);
+        assert_eq!(paths(&queue), ["0", "4"]);
+        assert_eq!(queue.current, Some(1));
+        assert_eq!(queue.state.selected(), Some(1));
+        assert_eq!(queue.mark, None);
+    }
+
+    #[test]
+    fn move_selected() {
+        let mut queue = queue(4);
+        queue.state.select(Some(1));
+        queue.mark = Some(2);
+        queue.move_selected(-1);
+        assert_eq!(paths(&queue), ["1", "2", "0", "3"]);
+        assert_eq!(queue.selected_range(), Some(0..=1));
+        // already at the top
+        queue.move_selected(-1);
+        assert_eq!(paths(&queue), ["1", "2", "0", "3"]);
+        queue.move_selected(1);
+        queue.move_selected(1);
+        assert_eq!(paths(&queue), ["0", "3", "1", "2"]);
+        queue.move_selected(1);
+        assert_eq!(paths(&queue), ["0", "3", "1", "2"]);
+    }
+
+    #[test]
+    fn cut_and_paste() {
+        let mut queue = queue(4);
+        queue.state.select(Some(0));
+        queue.cut_selected();
+        assert_eq!(paths(&queue), ["1", "2", "3"]);
+        queue.state.select(Some(1));
+        queue.paste();
+        assert_eq!(paths(&queue), ["1", "2", "0", "3"]);
+        assert_eq!(queue.state.selected(), Some(2));
+        // the clipboard stays for pasting again, without a selection it goes at the end
+        queue.state.select(None);
+        queue.paste();
+        assert_eq!(paths(&queue), ["1", "2", "0", "3", "0"]);
+
+        queue.state.select(Some(0));
+        queue.mark = Some(1);
+        queue.copy_selected();
+        assert_eq!(queue.clipboard.len(), 2);
+        assert_eq!(queue.songs.len(), 5);
+    }
+
+    #[test]
+    fn play_selected_next() {
+        let mut queue = queue(5);
+        queue.current = Some(1);
+        queue.state.select(Some(4));
+        queue.play_selected_next();
+        assert_eq!(paths(&queue), ["0", "1", "4", "2", "3"]);
+        assert_eq!(queue.current, Some(1));
+        assert_eq!(queue.following(Some(1)), Some(2));
+    }
+
+    #[test]
+    fn undo_edits() {
+        let mut queue = queue(3);
+        queue.current = Some(2);
+        queue.state.select(Some(0));
+        queue.remove_selected();
+        assert_eq!(queue.current, Some(1));
+        queue.move_selected(1);
+        assert_eq!(paths(&queue), ["2", "1"]);
+
+        assert!(queue.undo());
+        assert_eq!(paths(&queue), ["1", "2"]);
+        // the track being heard is looked up again in the restored queue
+        assert_eq!(queue.current, Some(1));
+        assert!(queue.undo());
+        assert_eq!(paths(&queue), ["0", "1", "2"]);
+        assert_eq!(queue.current, Some(2));
+
+        queue.clear();
+        assert!(queue.songs.is_empty());
+        assert!(queue.undo());
+        assert_eq!(paths(&queue), ["0", "1", "2"]);
+        // the append that filled the queue in the first place
+        assert!(queue.undo());
+        assert!(queue.songs.is_empty());
+        assert!(!queue.undo());
+    }
+
+    #[test]
+    fn undo_is_limited() {
+        let mut queue = queue(0);
+        for i in 0..UNDO_LIMIT + 10 {
+            queue.append(vec![song(&i.to_string())]);
+        }
+        let mut undone = 0;
+        while queue.undo() { undone += 1; }
+        assert_eq!(undone, UNDO_LIMIT);
+        // the oldest edits are the ones forgotten
+        assert_eq!(queue.songs.len(), 10);
+    }
+}
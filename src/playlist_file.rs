@@ -0,0 +1,213 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{index::write_atomically, playlist::Song};
+
+/// Extensions of the playlist files that are read, saving always writes `.m3u8`
+pub const PLAYLIST_EXTENSIONS: [&str; 3] = ["m3u", "m3u8", "pls"];
+
+/// A playlist file read into songs
+#[derive(Clone)]
+pub struct SavedPlaylist {
+    /// the file name without its extension
+    pub name: String,
+    pub path: PathBuf,
+    pub songs: Vec<Song>,
+    /// entries that point at files which don't exist or can't be read
+    pub missing: Vec<String>,
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| PLAYLIST_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// The entries of the M3U, M3U8 or PLS file at `path` as written in it, in order. Streams and other URLs are left
+/// out, `file://` URLs are turned into paths
+pub fn read_entries(path: &Path) -> io::Result<Vec<String>> {
+    let bytes = fs::read(path)?;
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        // plain .m3u is traditionally latin-1, every byte is the character with the same number
+        Err(err) => err.into_bytes().into_iter().map(char::from).collect(),
+    };
+    let entries = if extension == "pls" { parse_pls(&content) } else { parse_m3u(&content) };
+    Ok(entries.into_iter().filter_map(|e| file_entry(&e)).collect())
+}
+
+/// Finds the file a playlist entry means: absolute paths are taken as they are, relative ones are looked up next to
+/// the playlist first and then in each of the library `roots`
+pub fn resolve_entry(entry: &str, playlist_dir: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    let path = Path::new(entry);
+    if path.is_absolute() { return path.exists().then(|| path.to_path_buf()); }
+    std::iter::once(playlist_dir)
+        .chain(roots.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.exists())
+}
+
+/// Writes `songs` to `path` as an extended M3U8 playlist with absolute paths
+pub fn write_m3u8(path: &Path, songs: &[Song]) -> io::Result<()> {
+    write_atomically(path, |writer| {
+        writeln!(writer, "#EXTM3U")?;
+        for song in songs {
+            let display = match &song.artist {
+                Some(artist) => format!("{} - {}", artist, song.title),
+                None => song.title.clone(),
+            };
+            let file = fs::canonicalize(&song.path).unwrap_or_else(|_| PathBuf::from(&song.path));
+            // -1 is the conventional "length unknown"
+            let seconds = song.duration.map_or(-1, |d| d.as_secs() as i64);
+            writeln!(writer, "#EXTINF:{},{}", seconds, single_line(&display))?;
+            writeln!(writer, "{}", file.display())?;
+        }
+        Ok(())
+    })
+}
+
+/// Every line that isn't blank or a `#` directive is an entry
+fn parse_m3u(content: &str) -> Vec<String> {
+    content.lines()
+        .map(|l| l.trim_start_matches('\u{feff}').trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The `FileN=` keys of the `[playlist]` section, ordered by their number rather than where they appear
+fn parse_pls(content: &str) -> Vec<String> {
+    let mut entries: Vec<(u32, String)> = content.lines()
+        .filter_map(|l| {
+            let (key, value) = l.trim().split_once('=')?;
+            let number = key.trim().get(..4).filter(|k| k.eq_ignore_ascii_case("file"))
+                .and_then(|_| key.trim()[4..].parse().ok())?;
+            Some((number, value.trim().to_owned()))
+        })
+        .collect();
+    entries.sort_by_key(|(number, _)| *number);
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// The path an entry stands for, `None` for URLs that aren't local files
+fn file_entry(entry: &str) -> Option<String> {
+    match entry.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("file") => Some(percent_decode(rest)),
+        // a drive letter like C:\ has no "//" after the colon, anything else with a scheme is a stream
+        Some(_) => None,
+        None => Some(entry.to_owned()),
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Tags can contain line breaks, which would end the `#EXTINF` line early
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A directory of its own under the system's temporary directory, emptied first
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("horizon-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn m3u_entries() {
+        let content = "\u{feff}#EXTM3U\r\n#EXTINF:123,Artist - Title\r\n/music/a.mp3\r\n\r\n  b.flac  \n# comment\n";
+        assert_eq!(parse_m3u(content), ["/music/a.mp3", "b.flac"]);
+    }
+
+    #[test]
+    fn pls_entries_in_number_order() {
+        let content = "[playlist]\nFile2=b.mp3\nTitle2=B\nfile10 = c.mp3\nFile1=a.mp3\nFileX=x.mp3\nNumberOfEntries=3\n";
+        assert_eq!(parse_pls(content), ["a.mp3", "b.mp3", "c.mp3"]);
+    }
+
+    #[test]
+    fn url_entries() {
+        assert_eq!(file_entry("file:///music/a%20b%C3%A9.mp3").as_deref(), Some("/music/a bé.mp3"));
+        assert_eq!(file_entry("FILE:///x.mp3").as_deref(), Some("/x.mp3"));
+        assert_eq!(file_entry("http://radio.example/stream"), None);
+        assert_eq!(file_entry("C:\\Music\\a.mp3").as_deref(), Some("C:\\Music\\a.mp3"));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b"), "a b");
+        assert_eq!(percent_decode("%e2%82%ac"), "\u{20ac}");
+        // anything that isn't an escape stays as it is
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn resolving_entries() {
+        let dir = scratch_dir("resolve");
+        let (lists, root) = (dir.join("lists"), dir.join("root"));
+        fs::create_dir_all(lists.join("sub")).unwrap();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(lists.join("sub/near.mp3"), b"").unwrap();
+        fs::write(root.join("sub/near.mp3"), b"").unwrap();
+        fs::write(root.join("sub/far.mp3"), b"").unwrap();
+        let roots = [dir.join("elsewhere"), root.clone()];
+
+        // next to the playlist wins over the library roots
+        assert_eq!(resolve_entry("sub/near.mp3", &lists, &roots), Some(lists.join("sub/near.mp3")));
+        assert_eq!(resolve_entry("sub/far.mp3", &lists, &roots), Some(root.join("sub/far.mp3")));
+        assert_eq!(resolve_entry("sub/gone.mp3", &lists, &roots), None);
+        let absolute = root.join("sub/far.mp3");
+        assert_eq!(resolve_entry(&absolute.display().to_string(), &lists, &[]), Some(absolute));
+        assert_eq!(resolve_entry(&dir.join("gone.mp3").display().to_string(), &lists, &roots), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn written_playlists_read_back() {
+        let dir = scratch_dir("write");
+        let mut timed = Song::untagged("/music/a.mp3");
+        timed.artist = Some("Artist".to_owned());
+        timed.title = "Two\nLines".to_owned();
+        timed.duration = Some(Duration::from_millis(183_700));
+        let songs = [timed, Song::untagged("/music/b.mp3")];
+        let path = dir.join("saved.m3u8");
+        write_m3u8(&path, &songs).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "#EXTM3U\n#EXTINF:183,Artist - Two Lines\n/music/a.mp3\n#EXTINF:-1,b\n/music/b.mp3\n");
+        assert_eq!(read_entries(&path).unwrap(), ["/music/a.mp3", "/music/b.mp3"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
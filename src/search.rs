@@ -0,0 +1,190 @@
+use ratatui::widgets::ListState;
+
+use crate::playlist::Song;
+
+/// Most results kept for a query, more than anyone scrolls through
+const MAX_RESULTS: usize = 200;
+
+/// A song matching the search, with the positions (in chars) of the matched characters in each field for
+/// highlighting
+pub struct SearchResult {
+    /// index into `Library::songs`
+    pub song: usize,
+    pub score: i64,
+    pub title_hits: Vec<usize>,
+    pub artist_hits: Vec<usize>,
+    pub album_hits: Vec<usize>,
+}
+
+/// The `/` search popup: what has been typed so far and the songs matching it, best first
+#[derive(Default)]
+pub struct Search {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+    pub state: ListState,
+}
+
+impl Search {
+    /// Runs the query again over `songs`, call after every change to `query`
+    pub fn update(&mut self, songs: &[Song]) {
+        self.results = search(songs, &self.query);
+        self.state.select(if self.results.is_empty() { None } else { Some(0) });
+    }
+
+    pub fn selected(&self) -> Option<&SearchResult> {
+        self.state.selected().and_then(|i| self.results.get(i))
+    }
+}
+
+/// The songs matching `query`, best first. Every whitespace separated word of the query has to fuzzy-match the
+/// title, artist or album, so "daft one" finds "One More Time" by Daft Punk
+pub fn search(songs: &[Song], query: &str) -> Vec<SearchResult> {
+    let terms: Vec<Vec<char>> = query.split_whitespace().map(|t| t.chars().map(lowercase).collect()).collect();
+    if terms.is_empty() { return Vec::new(); }
+
+    let mut results: Vec<SearchResult> = songs.iter().enumerate().filter_map(|(i, song)| {
+        let fields = [
+            song.title.as_str(),
+            song.artist.as_deref().unwrap_or_default(),
+            song.album.as_deref().unwrap_or_default(),
+        ];
+        let mut hits: [Vec<usize>; 3] = Default::default();
+        let mut score = 0;
+        for term in &terms {
+            // the field matching best takes the term, the title winning ties
+            let (field, (term_score, positions)) = fields.iter().enumerate()
+                .filter_map(|(f, text)| fuzzy_match(term, text).map(|m| (f, m)))
+                .fold(None, |best: Option<(usize, (i64, Vec<usize>))>, m| match &best {
+                    Some((_, (s, _))) if *s >= m.1.0 => best,
+                    _ => Some(m),
+                })?;
+            score += term_score;
+            hits[field].extend(positions);
+        }
+        let [title_hits, artist_hits, album_hits] = hits;
+        Some(SearchResult { song: i, score, title_hits, artist_hits, album_hits })
+    }).collect();
+
+    results.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| songs[a.song].title.cmp(&songs[b.song].title)));
+    results.truncate(MAX_RESULTS);
+    results
+}
+
+/// Matches `pattern` (lowercase) as a subsequence of `text` ignoring case, returning a score and the char positions
+/// matched. Like fzf's first algorithm it finds the first place the whole pattern fits, then tightens the match
+/// by walking back from its end, which is cheap and picks the compact match in most real cases. Consecutive
+/// characters and ones at the start of a word score higher, gaps cost
+fn fuzzy_match(pattern: &[char], text: &str) -> Option<(i64, Vec<usize>)> {
+    if pattern.is_empty() { return Some((0, Vec::new())); }
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars.iter().copied().map(lowercase).collect();
+
+    let mut p = 0;
+    let mut end = None;
+    for (i, c) in lower.iter().enumerate() {
+        if *c == pattern[p] {
+            p += 1;
+            if p == pattern.len() {
+                end = Some(i);
+                break;
+            }
+        }
+    }
+    let end = end?;
+
+    let mut positions: Vec<usize> = Vec::with_capacity(pattern.len());
+    let mut p = pattern.len();
+    for i in (0..=end).rev() {
+        if lower[i] == pattern[p - 1] {
+            positions.push(i);
+            p -= 1;
+            if p == 0 { break; }
+        }
+    }
+    positions.reverse();
+
+    let mut score: i64 = 0;
+    let mut previous: Option<usize> = None;
+    for &i in &positions {
+        score += 16;
+        if i == 0 || !chars[i - 1].is_alphanumeric() { score += 8; }
+        match previous {
+            Some(prev) if prev + 1 == i => score += 8,
+            Some(prev) => score -= (i - prev - 1).min(8) as i64,
+            // matching further into the text counts a little less
+            None => score -= i.min(4) as i64,
+        }
+        previous = Some(i);
+    }
+    Some((score, positions))
+}
+
+/// Lowercase as a single char, so positions in the lowercased text are positions in the original
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str) -> Vec<char> {
+        text.chars().map(lowercase).collect()
+    }
+
+    fn score(query: &str, text: &str) -> i64 {
+        fuzzy_match(&pattern(query), text).unwrap().0
+    }
+
+    fn song(title: &str, artist: &str, album: &str) -> Song {
+        let mut song = Song::untagged(&format!("/music/{}.mp3", title));
+        song.title = title.to_owned();
+        song.artist = Some(artist.to_owned());
+        song.album = Some(album.to_owned());
+        song
+    }
+
+    #[test]
+    fn subsequence_ignoring_case() {
+        assert_eq!(fuzzy_match(&pattern("omt"), "One More Time").map(|m| m.1), Some(vec![0, 4, 9]));
+        assert_eq!(fuzzy_match(&pattern("ONE"), "phONe").map(|m| m.1), Some(vec![2, 3, 4]));
+        assert_eq!(fuzzy_match(&pattern("tmo"), "One More Time"), None);
+        assert_eq!(fuzzy_match(&[], "anything"), Some((0, Vec::new())));
+    }
+
+    #[test]
+    fn tightens_the_first_match() {
+        // "abc" fits first from the a at 0, walking back from the c finds the compact one
+        assert_eq!(fuzzy_match(&pattern("abc"), "a xx abc").map(|m| m.1), Some(vec![5, 6, 7]));
+    }
+
+    #[test]
+    fn ranking() {
+        // consecutive beats scattered
+        assert!(score("one", "One More Time") > score("one", "Oh No Eh"));
+        // word starts beat the middle of words
+        assert!(score("mt", "More Time") > score("mt", "smart"));
+        // early beats late
+        assert!(score("time", "Time After") > score("time", "After Time"));
+        // small gaps beat big ones
+        assert!(score("ab", "a-b") > score("ab", "a-----b"));
+    }
+
+    #[test]
+    fn every_word_has_to_match() {
+        let songs = [
+            song("One More Time", "Daft Punk", "Discovery"),
+            song("One", "Metallica", "...And Justice for All"),
+            song("Digital Love", "Daft Punk", "Discovery"),
+        ];
+        let results = search(&songs, "daft one");
+        assert_eq!(results.iter().map(|r| r.song).collect::<Vec<usize>>(), [0]);
+        assert_eq!(results[0].title_hits, [0, 1, 2]);
+        assert_eq!(results[0].artist_hits, [0, 1, 2, 3]);
+
+        let results = search(&songs, "one");
+        // both score the same, ties go by title
+        assert_eq!(results.iter().map(|r| r.song).collect::<Vec<usize>>(), [1, 0]);
+        assert!(search(&songs, "   ").is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{path::Path, time::Duration};
+
+use rodio::{source::SeekError, Source};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer, SignalSpec},
+    codecs::{Decoder, DecoderOptions},
+    errors::Error,
+    formats::{FormatReader, SeekMode, SeekTo},
+    units::TimeBase,
+};
+
+use crate::{
+    formats::{audio_track, codecs, probe, track_duration},
+    playlist::SongError,
+};
+
+/// Damaged packets in a row that are skipped over before giving up on the rest of the track
+const MAX_DECODE_ERRORS: usize = 3;
+
+/// A song decoded by symphonia for rodio to play, through the same demuxers and decoders the library probes files
+/// with. Samples come out interleaved in the channel order and rate of the file, with encoder delay and padding
+/// already cut off
+pub struct SongSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    total: Option<Duration>,
+    spec: SignalSpec,
+    /// the last decoded packet, handed out sample by sample
+    buffer: Option<SampleBuffer<f32>>,
+    /// next sample of `buffer` to hand out
+    offset: usize,
+    /// frames still to drop after a seek, the demuxer lands on a packet boundary before the target
+    skip: u64,
+    finished: bool,
+}
+
+impl SongSource {
+    pub fn open(path: &Path) -> Result<Self, SongError> {
+        let probed = probe(path)?;
+        let format = probed.format;
+        let Some(track) = audio_track(format.as_ref()) else { return Err(SongError::NoMetadata) };
+        let params = track.codec_params.clone();
+        let decoder = codecs().make(&params, &DecoderOptions::default())?;
+        let spec = SignalSpec::new(
+            params.sample_rate.unwrap_or(44100),
+            params.channels.unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+        );
+
+        let mut source = Self {
+            track_id: track.id,
+            time_base: params.time_base,
+            total: track_duration(&params),
+            format,
+            decoder,
+            spec,
+            buffer: None,
+            offset: 0,
+            skip: 0,
+            finished: false,
+        };
+        // the first packet tells the actual channels and rate, which not every container states up front
+        source.finished = !source.decode_next();
+        Ok(source)
+    }
+
+    /// Decodes packets into `buffer` until one has samples left to play, false once the track is over
+    fn decode_next(&mut self) -> bool {
+        let mut errors = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                // a chained Ogg stream starting its next part, which may come with different parameters
+                Err(Error::ResetRequired) => {
+                    let Some(track) = audio_track(self.format.as_ref()) else { return false };
+                    self.track_id = track.id;
+                    match codecs().make(&track.codec_params, &DecoderOptions::default()) {
+                        Ok(decoder) => self.decoder = decoder,
+                        Err(_) => return false,
+                    }
+                    continue;
+                }
+                // the end of the file, or it can't be read any further
+                Err(_) => return false,
+            };
+            if packet.track_id() != self.track_id { continue; }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(_)) if errors < MAX_DECODE_ERRORS => {
+                    errors += 1;
+                    continue;
+                }
+                Err(_) => return false,
+            };
+            let frames = decoded.frames() as u64;
+            let skipped = self.skip.min(frames);
+            self.skip -= skipped;
+            if skipped == frames { continue; }
+
+            let spec = *decoded.spec();
+            let channels = spec.channels.count();
+            let buffer = match self.buffer.take() {
+                Some(buffer) if buffer.capacity() >= decoded.capacity() * channels => buffer,
+                _ => SampleBuffer::new(decoded.capacity() as u64, spec),
+            };
+            let buffer = self.buffer.insert(buffer);
+            buffer.copy_interleaved_ref(decoded);
+            self.spec = spec;
+            self.offset = skipped as usize * channels;
+            return true;
+        }
+    }
+}
+
+impl Iterator for SongSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.finished { return None; }
+        let buffer = self.buffer.as_ref()?;
+        let sample = *buffer.samples().get(self.offset)?;
+        self.offset += 1;
+        // decoding ahead as soon as a packet runs out keeps `current_frame_len` honest about where the channels or
+        // rate may change
+        if self.offset >= buffer.len() { self.finished = !self.decode_next(); }
+        Some(sample)
+    }
+}
+
+impl Source for SongSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.finished { return Some(0); }
+        Some(self.buffer.as_ref().map_or(0, |b| b.len().saturating_sub(self.offset)))
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total
+    }
+
+    /// Lands on exactly the sample at `pos`, seeking in the container to the packet before it and decoding from
+    /// there
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let seeked = self.format
+            .seek(SeekMode::Accurate, SeekTo::Time { time: pos.into(), track_id: Some(self.track_id) })
+            .map_err(|err| SeekError::Other(Box::new(err)))?;
+        self.decoder.reset();
+        let behind = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.skip = match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(behind);
+                ((time.seconds as f64 + time.frac) * self.spec.rate as f64).round() as u64
+            }
+            None => behind,
+        };
+        self.finished = !self.decode_next();
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::{fs::File, io::BufReader, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::state_dir, grouping::View, index::write_atomically, library::SortOrder, playlist::{RepeatMode, Shuffle, Song}};
+
+/// What is carried over from one session to the next, stored in `state.json`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionState {
+    /// volume slider position, 0 to 1 (see `Player::set_volume`)
+    pub volume: f32,
+    pub muted: bool,
+    /// the queue as it was left, restored paused at `position` into its current track
+    pub queue: Vec<Song>,
+    pub current: Option<usize>,
+    pub position: Duration,
+    pub selected: Option<usize>,
+    pub history: Vec<usize>,
+    pub repeat: RepeatMode,
+    pub shuffle: Option<Shuffle>,
+    /// identifier paths of the library tree's expanded nodes and of its selection
+    pub tree_opened: Vec<Vec<String>>,
+    pub tree_selected: Vec<String>,
+    pub sort: SortOrder,
+    pub view: View,
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        Self {
+            volume: 1.,
+            muted: false,
+            queue: Vec::new(),
+            current: None,
+            position: Duration::ZERO,
+            selected: None,
+            history: Vec::new(),
+            repeat: RepeatMode::Off,
+            shuffle: None,
+            tree_opened: Vec::new(),
+            tree_selected: Vec::new(),
+            sort: SortOrder::Artist,
+            view: View::AlbumArtist,
+        }
+    }
+}
+
+impl SessionState {
+    /// Reads the last saved state, a missing or broken file just means starting fresh
+    pub fn load() -> Self {
+        state_file()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let Some(path) = state_file() else { return Ok(()) };
+        write_atomically(&path, |writer| Ok(serde_json::to_writer_pretty(writer, self)?))
+    }
+}
+
+fn state_file() -> Option<PathBuf> {
+    state_dir().map(|d| d.join("state.json"))
+}